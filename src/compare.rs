@@ -0,0 +1,112 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{HostResult, HostStatus, SCHEMA_VERSION, Statistics};
+
+/// A previous run's summary to compare against (--compare)
+pub struct Baseline {
+    stats: Statistics,
+}
+
+/// How the current run differs from the baseline. Deltas are current minus
+/// baseline, so positive values mean worse.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Comparison {
+    /// Timestamp of the baseline run
+    pub baseline_timestamp: u64,
+    pub avg_delta_microsecs: i64,
+    pub p95_delta_microsecs: i64,
+    /// Change in packet loss, in percentage points
+    pub packet_loss_delta_pct: f64,
+    /// Hosts that responded in the baseline but not now (only when the baseline
+    /// has per-host results)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newly_failed: Option<Vec<String>>,
+    /// Hosts that failed in the baseline but respond now (only when the
+    /// baseline has per-host results)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovered: Option<Vec<String>>,
+}
+
+impl Baseline {
+    /// Load rollping JSON output from `path`. For a file of JSON lines, such as
+    /// --ndjson output or an appended log, the last line is the baseline.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read baseline {:?}", path))?;
+        let value: Value = serde_json::from_str(&text)
+            .or_else(
+                |e| match text.lines().rev().find(|line| !line.trim().is_empty()) {
+                    Some(line) if text.trim().contains('\n') => serde_json::from_str(line),
+                    _ => Err(e),
+                },
+            )
+            .with_context(|| format!("Failed to parse baseline {:?} as JSON", path))?;
+        // Check the version first, since older output fails to parse for
+        // less obvious reasons
+        let version = value.get("schema_version").and_then(Value::as_str);
+        if version != Some(SCHEMA_VERSION) {
+            anyhow::bail!(
+                "baseline {:?} has schema version {}, but this rollping writes version {}",
+                path,
+                version.unwrap_or("(none)"),
+                SCHEMA_VERSION
+            );
+        }
+        let stats = Statistics::deserialize(value)
+            .with_context(|| format!("Failed to parse baseline {:?} as rollping output", path))?;
+        Ok(Baseline { stats })
+    }
+
+    /// Compare the current run's statistics, and its per-host `results`, with
+    /// the baseline
+    pub fn compare(&self, stats: &Statistics, results: &[HostResult]) -> Comparison {
+        let baseline = &self.stats;
+        let (newly_failed, recovered) = match &baseline.hosts {
+            Some(hosts) => {
+                let before = responsive_by_host(hosts.iter().map(|h| (h.host.as_str(), h.status)));
+                let now = responsive_by_host(results.iter().map(|r| (r.host.as_str(), r.status)));
+                let changed = |was: bool| {
+                    now.iter()
+                        .filter(|(host, is)| before.get(*host) == Some(&was) && **is != was)
+                        .map(|(host, _)| host.to_string())
+                        .collect()
+                };
+                (Some(changed(true)), Some(changed(false)))
+            }
+            None => (None, None),
+        };
+        Comparison {
+            baseline_timestamp: baseline.timestamp,
+            avg_delta_microsecs: stats.avg_microsecs - baseline.avg_microsecs,
+            p95_delta_microsecs: stats.p95_microsecs - baseline.p95_microsecs,
+            packet_loss_delta_pct: ((stats.packet_loss_pct - baseline.packet_loss_pct) * 100.0)
+                .round()
+                / 100.0,
+            newly_failed,
+            recovered,
+        }
+    }
+}
+
+/// Whether each host responded (on any of its addresses), leaving out hosts
+/// that were not pinged
+fn responsive_by_host<'a>(
+    hosts: impl Iterator<Item = (&'a str, HostStatus)>,
+) -> BTreeMap<&'a str, bool> {
+    let mut responsive = BTreeMap::new();
+    for (host, status) in hosts {
+        let pinged = match status {
+            HostStatus::Responsive => true,
+            HostStatus::Timeout | HostStatus::ResolutionFailed | HostStatus::DeadlineExceeded => {
+                false
+            }
+            HostStatus::Resolved | HostStatus::Skipped => continue,
+        };
+        *responsive.entry(host).or_insert(false) |= pinged;
+    }
+    responsive
+}
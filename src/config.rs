@@ -0,0 +1,262 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command, parser::ValueSource};
+
+/// A value from the config file
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Number(String),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Read the config file at `path` and turn each `key = value` into the flag it
+/// stands for, ready to go in front of the real command-line arguments. Keys
+/// are the option names with `_` or `-` (`timeout_secs` for `--timeout-secs`).
+/// A key is left out when `cli` already gives its option, or one that conflicts
+/// with it, so the command line wins instead of clashing with the file.
+pub fn flag_args(path: &Path, command: &Command, cli: &ArgMatches) -> Result<Vec<OsString>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {:?}", path))?;
+    let mut flags = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let parsed = parse_line(line)
+            .and_then(|entry| match entry {
+                Some((key, value)) => to_flags(&key, value, command, cli),
+                None => Ok(Vec::new()),
+            })
+            .with_context(|| format!("Failed to parse config file {:?} line {}", path, i + 1))?;
+        flags.extend(parsed);
+    }
+    Ok(flags)
+}
+
+/// Parse one line of the flat subset of TOML rollping reads: `key = value`,
+/// where the value is a string, number, boolean, or one-line array of those
+fn parse_line(line: &str) -> Result<Option<(String, Value)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    if line.starts_with('[') {
+        anyhow::bail!("tables are not supported; options go at the top level");
+    }
+    let (key, rest) = line.split_once('=').context("expected key = value")?;
+    let key = key.trim();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        anyhow::bail!("invalid key {:?}", key);
+    }
+    let (value, rest) = parse_value(rest.trim_start())?;
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        anyhow::bail!("unexpected {:?} after the value", rest);
+    }
+    Ok(Some((key.replace('-', "_"), value)))
+}
+
+/// Parse a value at the start of `s`, returning it and what follows
+fn parse_value(s: &str) -> Result<(Value, &str)> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    other => anyhow::bail!("unsupported escape \\{}", other.unwrap_or(' ')),
+                },
+                c => value.push(c),
+            }
+        }
+        anyhow::bail!("unterminated string");
+    }
+    if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest.find('\'').context("unterminated string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            if matches!(item, Value::Array(_)) {
+                anyhow::bail!("nested arrays are not supported");
+            }
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                anyhow::bail!("expected , or ] in array");
+            }
+        }
+    }
+
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => {
+            let number = word.replace('_', "");
+            if number.parse::<f64>().is_err() {
+                anyhow::bail!("invalid value {:?} (strings must be quoted)", word);
+            }
+            Value::Number(number)
+        }
+    };
+    Ok((value, rest))
+}
+
+/// The command-line arguments equivalent to setting `key` to `value`, or none
+/// if the command line overrides it
+fn to_flags(key: &str, value: Value, command: &Command, cli: &ArgMatches) -> Result<Vec<OsString>> {
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_id() == key)
+        .filter(|arg| !matches!(arg.get_id().as_str(), "config" | "help" | "version"))
+        .with_context(|| format!("unknown option {:?}", key))?;
+    if overridden(arg, command, cli) {
+        return Ok(Vec::new());
+    }
+    let flag = format!("--{}", arg.get_long().context("option has no long flag")?);
+
+    Ok(match (arg.get_action(), value) {
+        (ArgAction::Count, Value::Number(n)) => {
+            let times: usize = n
+                .parse()
+                .with_context(|| format!("{} needs a count", key))?;
+            vec![flag.into(); times]
+        }
+        (_, Value::Bool(true)) => vec![flag.into()],
+        (_, Value::Bool(false)) => Vec::new(),
+        (ArgAction::SetTrue, _) => anyhow::bail!("{} must be true or false", key),
+        (_, Value::Array(items)) => items
+            .into_iter()
+            .map(|item| scalar(item).map(|v| format!("{}={}", flag, v).into()))
+            .collect::<Result<_>>()?,
+        (_, value) => vec![format!("{}={}", flag, scalar(value)?).into()],
+    })
+}
+
+/// Whether the command line gives `arg`, or an option that cannot be used with
+/// it (conflicts may be declared on either side)
+fn overridden(arg: &Arg, command: &Command, cli: &ArgMatches) -> bool {
+    let given =
+        |other: &Arg| cli.value_source(other.get_id().as_str()) == Some(ValueSource::CommandLine);
+    given(arg)
+        || command.get_arg_conflicts_with(arg).into_iter().any(given)
+        || command.get_arguments().any(|other| {
+            given(other)
+                && command
+                    .get_arg_conflicts_with(other)
+                    .iter()
+                    .any(|conflict| conflict.get_id() == arg.get_id())
+        })
+}
+
+fn scalar(value: Value) -> Result<String> {
+    match value {
+        Value::String(s) | Value::Number(s) => Ok(s),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Array(_) => anyhow::bail!("nested arrays are not supported"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::Args;
+
+    /// The flags a config `line` turns into, given the command-line arguments `cli`
+    fn flags(line: &str, cli: &[&str]) -> Result<Vec<String>> {
+        let command = Args::command();
+        let cli = command
+            .clone()
+            .try_get_matches_from(["rollping"].iter().chain(cli))
+            .unwrap();
+        let (key, value) = parse_line(line)?.context("no option on the line")?;
+        Ok(to_flags(&key, value, &command, &cli)?
+            .into_iter()
+            .map(|flag| flag.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn values_become_flags() {
+        assert_eq!(flags("count = 5", &[]).unwrap(), ["--count=5"]);
+        assert_eq!(
+            flags("timeout-secs = 1.5 # fast", &[]).unwrap(),
+            ["--timeout-secs=1.5"]
+        );
+        assert_eq!(flags("per_host = true", &[]).unwrap(), ["--per-host"]);
+        assert!(flags("per_host = false", &[]).unwrap().is_empty());
+        assert_eq!(
+            flags("verbose = 2", &[]).unwrap(),
+            ["--verbose", "--verbose"]
+        );
+        assert_eq!(
+            flags(r#"geo_only_country = ["US", 'DE']"#, &[]).unwrap(),
+            ["--geo-only-country=US", "--geo-only-country=DE"]
+        );
+        assert_eq!(flags(r#"input = "a\"b""#, &[]).unwrap(), [r#"--input=a"b"#]);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        assert!(parse_line("").unwrap().is_none());
+        assert!(parse_line("   # just a comment").unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_lines_are_rejected() {
+        for line in [
+            "[ping]",
+            "count 5",
+            "count = abc",
+            "count = 5 6",
+            "bogus = 1",
+            "config = \"other.toml\"",
+            "per_host = 1",
+            "input = \"unterminated",
+            "hosts = [[1]]",
+        ] {
+            assert!(flags(line, &[]).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn the_command_line_wins() {
+        assert!(flags("count = 5", &["-c", "1"]).unwrap().is_empty());
+        assert!(flags("ipv4 = true", &["-6"]).unwrap().is_empty());
+        assert!(
+            flags("watch = 1", &["--deadline-secs", "2"])
+                .unwrap()
+                .is_empty()
+        );
+        // The conflict is declared on --deadline-secs, not --watch
+        assert!(
+            flags("deadline_secs = 2", &["--watch", "1"])
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(flags("count = 5", &["-6"]).unwrap(), ["--count=5"]);
+    }
+}
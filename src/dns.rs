@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+use tracing::debug;
+
+/// How long to wait for the DNS server to answer a single query
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Largest DNS message accepted over UDP without EDNS
+const DNS_MAX_MESSAGE_SIZE: usize = 512;
+
+/// Where the system resolver's DNS servers are listed
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Addresses already resolved, shared by every ping task (and --watch cycle)
+/// so each name is only looked up once per `ttl`
+pub struct DnsCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl DnsCache {
+    /// A zero `ttl` disables caching
+    pub fn new(ttl: Duration) -> Self {
+        DnsCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Addresses cached for `host`, unless they have expired
+    pub fn get(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(host) {
+            Some((addrs, resolved)) if resolved.elapsed() < self.ttl => Some(addrs.clone()),
+            Some(_) => {
+                debug!("Cached addresses for {} expired", host);
+                entries.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, host: &str, addrs: &[IpAddr]) {
+        if !self.ttl.is_zero() {
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(host.to_string(), (addrs.to_vec(), Instant::now()));
+        }
+    }
+}
+
+/// Minimal stub resolver that sends A and AAAA queries to one DNS server over
+/// UDP, instead of going through the system resolver
+pub struct Resolver {
+    server: SocketAddr,
+}
+
+impl Resolver {
+    pub fn new(server: SocketAddr) -> Self {
+        Resolver { server }
+    }
+
+    /// Use the first DNS server the system resolver is configured with
+    pub fn from_system() -> Result<Self> {
+        let conf = fs::read_to_string(RESOLV_CONF)
+            .with_context(|| format!("Failed to read {}", RESOLV_CONF))?;
+        let server = conf
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|addr| addr.trim().parse::<IpAddr>().ok())
+            .with_context(|| format!("no nameserver found in {}", RESOLV_CONF))?;
+        debug!("Using DNS server {} from {}", server, RESOLV_CONF);
+        Ok(Resolver::new(SocketAddr::new(server, 53)))
+    }
+
+    /// Resolve `host` to its IPv4 addresses followed by its IPv6 addresses. A
+    /// family whose query fails is left out; only if both fail is it an error.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+        match (v4, v6) {
+            (Ok(mut addrs), Ok(v6)) => {
+                addrs.extend(v6);
+                Ok(addrs)
+            }
+            (Ok(addrs), Err(e)) | (Err(e), Ok(addrs)) => {
+                debug!("Only one address family resolved for {}: {:#}", host, e);
+                Ok(addrs)
+            }
+            (Err(e), Err(_)) => Err(e),
+        }
+    }
+
+    /// Look up the PTR record for `ip`, or `None` if it has none
+    pub async fn reverse(&self, ip: IpAddr) -> Result<Option<String>> {
+        let response = self.exchange(&reverse_name(ip), TYPE_PTR).await?;
+        let Some(records) = answer_records(&response)? else {
+            return Ok(None);
+        };
+        records
+            .into_iter()
+            .find(|(rtype, _)| *rtype == TYPE_PTR)
+            .map(|(_, rdata)| read_name(&response, rdata.start))
+            .transpose()
+    }
+
+    /// Send one query for `qtype` records and return the addresses in the answer
+    async fn query(&self, host: &str, qtype: u16) -> Result<Vec<IpAddr>> {
+        let response = self.exchange(host, qtype).await?;
+        let records = answer_records(&response)?.context("no such host")?;
+        let mut addrs = Vec::new();
+        // CNAMEs and anything else are skipped; the server follows them for us
+        for (rtype, rdata) in records {
+            let rdata = &response[rdata];
+            match (rtype, rdata.len()) {
+                (TYPE_A, 4) => addrs.push(IpAddr::from(<[u8; 4]>::try_from(rdata)?)),
+                (TYPE_AAAA, 16) => addrs.push(IpAddr::from(<[u8; 16]>::try_from(rdata)?)),
+                _ => {}
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Send one query and return the raw response that answers it, asking again
+    /// over TCP if the UDP answer was truncated
+    async fn exchange(&self, host: &str, qtype: u16) -> Result<Vec<u8>> {
+        let id: u16 = rand::random();
+        let request = encode_query(id, host, qtype)?;
+
+        let bind_addr: SocketAddr = match self.server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(self.server).await?;
+        socket.send(&request).await?;
+
+        let mut buf = [0u8; DNS_MAX_MESSAGE_SIZE];
+        loop {
+            let len = timeout(DNS_QUERY_TIMEOUT, socket.recv(&mut buf))
+                .await
+                .with_context(|| format!("no answer from {}", self.server))??;
+            let response = &buf[..len];
+            // Ignore stray datagrams that don't answer our query
+            if response.len() >= 2 && u16::from_be_bytes([response[0], response[1]]) == id {
+                if is_truncated(response) {
+                    debug!(
+                        "Truncated answer for {} from {}, retrying over TCP",
+                        host, self.server
+                    );
+                    return self.exchange_tcp(id, &request).await;
+                }
+                return Ok(response.to_vec());
+            }
+        }
+    }
+
+    /// Send a query over TCP, where answers are framed by a two-byte length and
+    /// so can exceed the UDP limit
+    async fn exchange_tcp(&self, id: u16, request: &[u8]) -> Result<Vec<u8>> {
+        let exchange = async {
+            let mut stream = TcpStream::connect(self.server).await?;
+            let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(request);
+            stream.write_all(&framed).await?;
+            let len = stream.read_u16().await? as usize;
+            let mut response = vec![0; len];
+            stream.read_exact(&mut response).await?;
+            anyhow::Ok(response)
+        };
+        let response = timeout(DNS_QUERY_TIMEOUT, exchange)
+            .await
+            .with_context(|| format!("no answer from {} over TCP", self.server))??;
+        if response.len() < 2 || u16::from_be_bytes([response[0], response[1]]) != id {
+            anyhow::bail!("mismatched DNS response from {} over TCP", self.server);
+        }
+        if is_truncated(&response) {
+            anyhow::bail!("truncated DNS response from {} over TCP", self.server);
+        }
+        Ok(response)
+    }
+}
+
+/// Whether the server set the TC flag, meaning the answer did not fit
+fn is_truncated(response: &[u8]) -> bool {
+    response.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// Build a recursive query for a single question
+fn encode_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(DNS_MAX_MESSAGE_SIZE);
+    message.extend_from_slice(&id.to_be_bytes());
+    // Flags: standard query with recursion desired
+    message.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no answer, authority, or additional records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("invalid host name {:?}", host);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// The type and data range of each record in an answer section
+type Records = Vec<(u16, Range<usize>)>;
+
+/// The records in the answer section of a response, or `None` if the name does
+/// not exist
+fn answer_records(response: &[u8]) -> Result<Option<Records>> {
+    let header = response.get(..12).context("truncated DNS response")?;
+    let rcode = header[3] & 0x0f;
+    match rcode {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(None),
+        _ => anyhow::bail!("server returned error code {}", rcode),
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(response, pos)?;
+        let fields = response
+            .get(pos..pos + 10)
+            .context("truncated DNS response")?;
+        let rtype = u16::from_be_bytes([fields[0], fields[1]]);
+        let rdlength = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        pos += 10;
+        if response.len() < pos + rdlength {
+            anyhow::bail!("truncated DNS response");
+        }
+        records.push((rtype, pos..pos + rdlength));
+        pos += rdlength;
+    }
+    Ok(Some(records))
+}
+
+/// The name PTR records for `ip` are stored under, e.g. `4.3.2.1.in-addr.arpa`
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                let _ = write!(name, "{:x}.{:x}.", byte & 0x0f, byte >> 4);
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Read the (possibly compressed) name starting at `pos`, without the trailing dot
+fn read_name(message: &[u8], mut pos: usize) -> Result<String> {
+    let mut labels = Vec::new();
+    // Each pointer must go backwards, which rules out loops
+    let mut limit = pos;
+    loop {
+        let len = *message.get(pos).context("truncated DNS response")?;
+        match len {
+            0 => return Ok(labels.join(".")),
+            len if len & 0xc0 == 0xc0 => {
+                let low = *message.get(pos + 1).context("truncated DNS response")?;
+                let target = (((len & 0x3f) as usize) << 8) | low as usize;
+                if target >= limit {
+                    anyhow::bail!("invalid compression pointer in DNS response");
+                }
+                pos = target;
+                limit = target;
+            }
+            len => {
+                let label = message
+                    .get(pos + 1..pos + 1 + len as usize)
+                    .context("truncated DNS response")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len as usize;
+            }
+        }
+    }
+}
+
+/// Return the offset just past the (possibly compressed) name starting at `pos`
+fn skip_name(message: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *message.get(pos).context("truncated DNS response")?;
+        match len {
+            0 => return Ok(pos + 1),
+            // A compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to an A query for example.com with `answers` recorded `count` times
+    fn response(flags: u16, count: u16, answers: &[u8]) -> Vec<u8> {
+        let mut message = encode_query(0x1234, "example.com", TYPE_A).unwrap();
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&count.to_be_bytes());
+        message.extend_from_slice(answers);
+        message
+    }
+
+    /// A resource record for the name at offset 12, via a compression pointer
+    fn record(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut record = vec![0xc0, 12];
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&60u32.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    #[test]
+    fn query_round_trips() {
+        let query = encode_query(0xbeef, "www.example.com.", TYPE_AAAA).unwrap();
+        assert_eq!(&query[..4], &[0xbe, 0xef, 0x01, 0x00]);
+        assert_eq!(&query[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read_name(&query, 12).unwrap(), "www.example.com");
+        let end = skip_name(&query, 12).unwrap();
+        assert_eq!(&query[end..], &[0, 28, 0, 1]);
+    }
+
+    #[test]
+    fn query_rejects_bad_labels() {
+        assert!(encode_query(1, "a..b", TYPE_A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn answers_follow_compression_pointers() {
+        // A CNAME to "www" + pointer to example.com, then an A record
+        let mut answers = record(5, &[3, b'w', b'w', b'w', 0xc0, 12]);
+        answers.extend(record(TYPE_A, &[192, 0, 2, 1]));
+        let message = response(0x8180, 2, &answers);
+
+        let records = answer_records(&message).unwrap().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, 5);
+        assert_eq!(
+            read_name(&message, records[0].1.start).unwrap(),
+            "www.example.com"
+        );
+        assert_eq!(records[1].0, TYPE_A);
+        assert_eq!(&message[records[1].1.clone()], &[192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn pointer_loops_are_rejected() {
+        let mut message = response(0x8180, 0, &[]);
+        let at = message.len();
+        message.extend_from_slice(&[0xc0 | (at >> 8) as u8, at as u8]);
+        assert!(read_name(&message, at).is_err());
+    }
+
+    #[test]
+    fn missing_names_and_errors() {
+        assert!(answer_records(&response(0x8183, 0, &[])).unwrap().is_none());
+        assert!(answer_records(&response(0x8182, 0, &[])).is_err());
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        assert!(answer_records(&[0x12, 0x34, 0x81]).is_err());
+        // Promises an answer that isn't there
+        assert!(answer_records(&response(0x8180, 1, &[])).is_err());
+        // Record data shorter than its length says
+        let mut answers = record(TYPE_A, &[192, 0, 2, 1]);
+        answers.truncate(answers.len() - 1);
+        assert!(answer_records(&response(0x8180, 1, &answers)).is_err());
+        assert!(read_name(&[3, b'w', b'w'], 0).is_err());
+    }
+
+    #[test]
+    fn truncation_flag() {
+        assert!(is_truncated(&response(0x8380, 0, &[])));
+        assert!(!is_truncated(&response(0x8180, 0, &[])));
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name("192.0.2.1".parse().unwrap()),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("2001:db8::1".parse().unwrap()),
+            format!("1.{}8.b.d.0.1.0.0.2.ip6.arpa", "0.".repeat(23))
+        );
+    }
+}
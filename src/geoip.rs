@@ -1,10 +1,17 @@
 use std::{
+    collections::HashSet,
     fs,
+    io::Read,
     net::IpAddr,
     path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
 use maxminddb::{Reader, geoip2};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
@@ -12,74 +19,241 @@ use tracing::{debug, info, warn};
 const GEOIP_CACHE_DIR: &str = "/tmp/rollping";
 const GEOIP_DB_FILENAME: &str = "GeoLite2-City.mmdb";
 const GEOIP_DB_URL: &str = "https://github.com/P3TERX/GeoLite.mmdb/raw/download/GeoLite2-City.mmdb";
+const GEOIP_COUNTRY_DB_FILENAME: &str = "GeoLite2-Country.mmdb";
+const GEOIP_COUNTRY_DB_URL: &str =
+    "https://github.com/P3TERX/GeoLite.mmdb/raw/download/GeoLite2-Country.mmdb";
+const GEOIP_ASN_DB_FILENAME: &str = "GeoLite2-ASN.mmdb";
+const GEOIP_ASN_DB_URL: &str =
+    "https://github.com/P3TERX/GeoLite.mmdb/raw/download/GeoLite2-ASN.mmdb";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Location {
     pub country: Option<String>,
     pub country_code: Option<String>,
     pub city: Option<String>,
+    /// State or region: the largest subdivision's name, or its ISO code
+    pub subdivision: Option<String>,
+    pub postal_code: Option<String>,
+    /// IANA time zone, e.g. `America/Denver`
+    pub time_zone: Option<String>,
+    /// Country the address serves when it differs from where it is, such as a
+    /// military base abroad
+    pub represented_country: Option<String>,
     pub latitude: Option<f64>,
     pub longitude: Option<f64>,
+    /// Autonomous system number (from the ASN database)
+    pub asn: Option<u32>,
+    /// Autonomous system organization (from the ASN database)
+    pub org: Option<String>,
+}
+
+/// How detailed geolocation is, and so which database is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GeoLevel {
+    /// City, country, and coordinates (GeoLite2-City, ~60 MB)
+    City,
+    /// Country only (GeoLite2-Country, a much smaller download)
+    Country,
+}
+
+/// Settings for locating and refreshing the GeoIP database
+#[derive(Debug, Clone)]
+pub struct GeoIpOptions {
+    /// User-supplied database, used as-is and never downloaded
+    pub db_path: Option<PathBuf>,
+    /// User-supplied ASN database, used as-is and never downloaded
+    pub asn_db_path: Option<PathBuf>,
+    /// Age after which the cached database is re-downloaded
+    pub max_age: Duration,
+    pub level: GeoLevel,
+    /// Never download or refresh databases; only use what is already cached
+    pub offline: bool,
+    /// Connect and read timeout for downloads
+    pub timeout: Duration,
 }
 
 pub struct GeoIpClient {
+    level: GeoLevel,
     reader: Option<Reader<Vec<u8>>>,
+    asn_reader: Option<Reader<Vec<u8>>>,
 }
 
 impl GeoIpClient {
-    pub fn new() -> Self {
-        match Self::initialize() {
+    pub fn new(options: &GeoIpOptions) -> Self {
+        let reader = match Self::initialize(options) {
             Ok(reader) => {
                 info!("GeoIP database loaded successfully");
-                GeoIpClient {
-                    reader: Some(reader),
-                }
+                Some(reader)
             }
             Err(e) => {
                 warn!(
                     "Failed to initialize GeoIP: {}. Geolocation will be disabled.",
                     e
                 );
-                GeoIpClient { reader: None }
+                None
+            }
+        };
+
+        let asn_reader = match Self::initialize_asn(options) {
+            Ok(reader) => {
+                if reader.is_some() {
+                    info!("GeoIP ASN database loaded successfully");
+                }
+                reader
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to initialize GeoIP ASN database: {}. ASN lookups will be disabled.",
+                    e
+                );
+                None
             }
+        };
+
+        GeoIpClient {
+            level: options.level,
+            reader,
+            asn_reader,
         }
     }
 
-    fn initialize() -> Result<Reader<Vec<u8>>> {
-        let db_path = Self::get_db_path();
+    fn initialize(options: &GeoIpOptions) -> Result<Reader<Vec<u8>>> {
+        // A user-supplied database is never downloaded or refreshed
+        if let Some(db_path) = &options.db_path {
+            if !db_path.exists() {
+                anyhow::bail!("GeoIP database not found at {:?}", db_path);
+            }
+            debug!("Loading user-supplied GeoIP database from {:?}", db_path);
+            return Self::open_user_db(db_path);
+        }
+
+        match options.level {
+            GeoLevel::City => Self::open_cached(GEOIP_DB_FILENAME, GEOIP_DB_URL, options),
+            GeoLevel::Country => {
+                Self::open_cached(GEOIP_COUNTRY_DB_FILENAME, GEOIP_COUNTRY_DB_URL, options)
+            }
+        }
+    }
 
-        // Try to load existing database
+    /// The ASN database. It is downloaded alongside the main database, but never
+    /// next to a user-supplied one, so with --geo-db there is only the one given
+    /// with --geo-asn-db.
+    fn initialize_asn(options: &GeoIpOptions) -> Result<Option<Reader<Vec<u8>>>> {
+        if let Some(db_path) = &options.asn_db_path {
+            debug!(
+                "Loading user-supplied GeoIP ASN database from {:?}",
+                db_path
+            );
+            return Self::open_user_db(db_path).map(Some);
+        }
+        if options.db_path.is_some() {
+            debug!("No ASN database given alongside the user-supplied GeoIP database");
+            return Ok(None);
+        }
+        Self::open_cached(GEOIP_ASN_DB_FILENAME, GEOIP_ASN_DB_URL, options).map(Some)
+    }
+
+    /// Open a user-supplied database, decompressing it into memory first if it is
+    /// gzip-compressed (by extension or magic bytes)
+    fn open_user_db(db_path: &Path) -> Result<Reader<Vec<u8>>> {
+        let mut magic = [0u8; 4];
+        let magic_len = fs::File::open(db_path)
+            .and_then(|file| file.take(magic.len() as u64).read(&mut magic))
+            .context("Failed to open GeoIP database")?;
+        let magic = &magic[..magic_len];
+        let extension = db_path.extension().and_then(|ext| ext.to_str());
+
+        if magic.starts_with(&ZSTD_MAGIC) || extension == Some("zst") {
+            anyhow::bail!(
+                "zstd-compressed GeoIP databases are not supported; decompress {:?} with \
+                 `zstd -d` or recompress it with gzip",
+                db_path
+            );
+        }
+        if !magic.starts_with(&GZIP_MAGIC) && extension != Some("gz") {
+            return Reader::open_readfile(db_path).context("Failed to open GeoIP database");
+        }
+
+        let file = fs::File::open(db_path).context("Failed to open GeoIP database")?;
+        let mut bytes = Vec::new();
+        GzDecoder::new(file)
+            .read_to_end(&mut bytes)
+            .context("Failed to decompress GeoIP database")?;
+        debug!(
+            "Decompressed GeoIP database {:?} to {} bytes",
+            db_path,
+            bytes.len()
+        );
+        Reader::from_source(bytes).context("Failed to open GeoIP database")
+    }
+
+    /// Open a database from the cache directory, downloading it from `url` if it is
+    /// missing or older than the maximum age (unless offline)
+    fn open_cached(filename: &str, url: &str, options: &GeoIpOptions) -> Result<Reader<Vec<u8>>> {
+        let db_path = Path::new(GEOIP_CACHE_DIR).join(filename);
+        let max_age = options.max_age;
+
+        // Try to load existing database, refreshing it first if it is stale
         if db_path.exists() {
+            if options.offline {
+                debug!("Offline, not checking whether {} needs a refresh", filename);
+            } else if Self::is_stale(&db_path, max_age) {
+                info!("{} is older than {:?}, refreshing...", filename, max_age);
+                if let Err(e) = Self::download_database(&db_path, url, options.timeout) {
+                    warn!("Failed to refresh GeoIP database: {}. Using stale copy.", e);
+                }
+            }
+
             debug!("Loading existing GeoIP database from {:?}", db_path);
             let reader = Reader::open_readfile(&db_path)
                 .context("Failed to open existing GeoIP database")?;
             return Ok(reader);
         }
 
+        if options.offline {
+            anyhow::bail!(
+                "{} not found in {} and --geo-offline forbids downloading it",
+                filename,
+                GEOIP_CACHE_DIR
+            );
+        }
+
         // Database doesn't exist, try to download it
-        info!("GeoIP database not found, downloading from mirror...");
-        Self::download_database(&db_path)?;
+        info!("{} not found, downloading from mirror...", filename);
+        Self::download_database(&db_path, url, options.timeout)?;
 
         let reader =
             Reader::open_readfile(&db_path).context("Failed to open downloaded GeoIP database")?;
         Ok(reader)
     }
 
-    fn get_db_path() -> PathBuf {
-        Path::new(GEOIP_CACHE_DIR).join(GEOIP_DB_FILENAME)
+    fn is_stale(db_path: &Path, max_age: Duration) -> bool {
+        match fs::metadata(db_path).and_then(|m| m.modified()) {
+            Ok(modified) => SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age > max_age),
+            Err(e) => {
+                debug!("Failed to read GeoIP database modification time: {}", e);
+                false
+            }
+        }
     }
 
-    fn download_database(db_path: &Path) -> Result<()> {
+    fn download_database(db_path: &Path, url: &str, timeout: Duration) -> Result<()> {
         // Create cache directory if it doesn't exist
         if let Some(parent) = db_path.parent() {
             fs::create_dir_all(parent).context("Failed to create GeoIP cache directory")?;
         }
 
-        debug!("Downloading GeoIP database from {}", GEOIP_DB_URL);
+        debug!("Downloading GeoIP database from {}", url);
 
         // Download the database
-        let response =
-            reqwest::blocking::get(GEOIP_DB_URL).context("Failed to download GeoIP database")?;
+        let mut response = http_client(timeout)?
+            .get(url)
+            .send()
+            .context("Failed to download GeoIP database")?;
 
         if !response.status().is_success() {
             anyhow::bail!(
@@ -88,18 +262,69 @@ impl GeoIpClient {
             );
         }
 
-        let bytes = response
-            .bytes()
+        // Read through io::Read so the timeout applies to each read rather than
+        // to the whole (large) body
+        let mut bytes = Vec::new();
+        response
+            .read_to_end(&mut bytes)
             .context("Failed to read GeoIP database response")?;
 
-        // Write to disk
-        fs::write(db_path, bytes).context("Failed to write GeoIP database to disk")?;
+        // Make sure we got a real database, not a truncated file or an HTML error page
+        Reader::from_source(&bytes[..]).with_context(|| {
+            format!(
+                "Downloaded GeoIP database is not a valid MaxMind database ({} bytes)",
+                bytes.len()
+            )
+        })?;
+
+        // Write to a temporary file and rename it over the old one, so a failed
+        // write never clobbers a previous good copy
+        let tmp_path = db_path.with_extension("mmdb.tmp");
+        fs::write(&tmp_path, &bytes).context("Failed to write GeoIP database to disk")?;
+        fs::rename(&tmp_path, db_path).context("Failed to move GeoIP database into place")?;
 
         info!("GeoIP database downloaded successfully to {:?}", db_path);
         Ok(())
     }
 
     pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let mut location = match self.level {
+            GeoLevel::City => self.lookup_city(ip),
+            GeoLevel::Country => self.lookup_country(ip),
+        };
+        if let Some((asn, org)) = self.lookup_asn(ip) {
+            let loc = location.get_or_insert_with(Location::default);
+            loc.asn = asn;
+            loc.org = org;
+        }
+        location
+    }
+
+    fn lookup_asn(&self, ip: IpAddr) -> Option<(Option<u32>, Option<String>)> {
+        let reader = self.asn_reader.as_ref()?;
+
+        match reader.lookup::<geoip2::Asn>(ip) {
+            Ok(Some(asn_data)) => {
+                debug!(
+                    "GeoIP ASN lookup for {}: asn={:?}, org={:?}",
+                    ip, asn_data.autonomous_system_number, asn_data.autonomous_system_organization
+                );
+                Some((
+                    asn_data.autonomous_system_number,
+                    asn_data
+                        .autonomous_system_organization
+                        .map(|s| s.to_string()),
+                ))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                debug!("GeoIP ASN lookup failed for {}: {}", ip, e);
+                None
+            }
+        }
+    }
+
+    fn lookup_city(&self, ip: IpAddr) -> Option<Location> {
         let reader = self.reader.as_ref()?;
 
         match reader.lookup::<geoip2::City>(ip) {
@@ -139,18 +364,82 @@ impl GeoIpClient {
                     .and_then(|n| n.get("en"))
                     .map(|s| s.to_string());
 
-                let (latitude, longitude) = city_data
+                // Subdivisions go from largest to smallest (state, then county)
+                let subdivision = city_data
+                    .subdivisions
+                    .as_ref()
+                    .and_then(|s| s.first())
+                    .and_then(|s| {
+                        s.names
+                            .as_ref()
+                            .and_then(|n| n.get("en").copied())
+                            .or(s.iso_code)
+                    })
+                    .map(|s| s.to_string());
+
+                let postal_code = city_data
+                    .postal
+                    .as_ref()
+                    .and_then(|p| p.code)
+                    .map(|s| s.to_string());
+
+                let represented_country = city_data
+                    .represented_country
+                    .as_ref()
+                    .and_then(|c| c.names.as_ref())
+                    .and_then(|n| n.get("en"))
+                    .map(|s| s.to_string());
+
+                let (latitude, longitude, time_zone) = city_data
                     .location
                     .as_ref()
-                    .map(|l| (l.latitude, l.longitude))
-                    .unwrap_or((None, None));
+                    .map(|l| (l.latitude, l.longitude, l.time_zone))
+                    .unwrap_or((None, None, None));
 
                 Some(Location {
                     country,
                     country_code,
                     city: city_name,
+                    subdivision,
+                    postal_code,
+                    time_zone: time_zone.map(|s| s.to_string()),
+                    represented_country,
                     latitude,
                     longitude,
+                    asn: None,
+                    org: None,
+                })
+            }
+            Ok(None) => {
+                debug!("GeoIP lookup for {} returned no data", ip);
+                None
+            }
+            Err(e) => {
+                debug!("GeoIP lookup failed for {}: {}", ip, e);
+                None
+            }
+        }
+    }
+
+    fn lookup_country(&self, ip: IpAddr) -> Option<Location> {
+        let reader = self.reader.as_ref()?;
+
+        match reader.lookup::<geoip2::Country>(ip) {
+            Ok(Some(country_data)) => {
+                let country = country_data.country.as_ref();
+                debug!(
+                    "GeoIP country lookup for {}: {:?}",
+                    ip,
+                    country.and_then(|c| c.iso_code)
+                );
+
+                Some(Location {
+                    country: country
+                        .and_then(|c| c.names.as_ref())
+                        .and_then(|n| n.get("en"))
+                        .map(|s| s.to_string()),
+                    country_code: country.and_then(|c| c.iso_code).map(|s| s.to_string()),
+                    ..Location::default()
                 })
             }
             Ok(None) => {
@@ -169,33 +458,111 @@ impl GeoIpClient {
     }
 }
 
-/// Get the public IP address of the current machine
-pub fn get_public_ip() -> Result<IpAddr> {
+/// The countries targets may be pinged in (--geo-only-country)
+#[derive(Debug)]
+pub struct Geofence {
+    /// Allowed ISO 3166-1 alpha-2 codes, uppercase
+    countries: HashSet<String>,
+    /// Skip hosts that cannot be placed in a country, rather than pinging them
+    skip_ungeolocated: bool,
+}
+
+impl Geofence {
+    pub fn new(countries: &[String], skip_ungeolocated: bool) -> Self {
+        Geofence {
+            countries: countries.iter().map(|c| c.to_ascii_uppercase()).collect(),
+            skip_ungeolocated,
+        }
+    }
+
+    /// Whether a host geolocated to `location` may be pinged
+    pub fn allows(&self, location: Option<&Location>) -> bool {
+        match location.and_then(|l| l.country_code.as_deref()) {
+            Some(code) => self.countries.contains(&code.to_ascii_uppercase()),
+            None => !self.skip_ungeolocated,
+        }
+    }
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Great-circle distance between two locations, or `None` if either lacks coordinates
+pub fn haversine_km(from: &Location, to: &Location) -> Option<f64> {
+    let (lat1, lon1) = (from.latitude?.to_radians(), from.longitude?.to_radians());
+    let (lat2, lon2) = (to.latitude?.to_radians(), to.longitude?.to_radians());
+
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    Some(2.0 * EARTH_RADIUS_KM * a.sqrt().asin())
+}
+
+/// HTTP client whose connects and reads give up after `timeout`
+fn http_client(timeout: Duration) -> Result<reqwest::blocking::Client> {
+    reqwest::blocking::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()
+        .context("Failed to build HTTP client")
+}
+
+/// Get the public IP address of the current machine, giving each service at
+/// most `timeout` to answer
+pub fn get_public_ip(timeout: Duration) -> Result<IpAddr> {
     debug!("Detecting public IP address...");
 
-    // Try multiple services for reliability
+    // Ask several services at once, so one that hangs doesn't hold up the rest
     let services = [
         "https://api.ipify.org",
         "https://icanhazip.com",
         "https://ifconfig.me/ip",
     ];
 
-    for service in &services {
-        match reqwest::blocking::get(*service) {
-            Ok(response) => {
-                if let Ok(ip_str) = response.text()
-                    && let Ok(ip) = ip_str.trim().parse::<IpAddr>()
-                {
-                    debug!("Detected public IP: {} (from {})", ip, service);
-                    return Ok(ip);
-                }
-            }
-            Err(e) => {
-                debug!("Failed to get IP from {}: {}", service, e);
-                continue;
+    let client = http_client(timeout)?;
+
+    let (tx, rx) = mpsc::channel();
+    for (index, service) in services.into_iter().enumerate() {
+        let client = client.clone();
+        let tx = tx.clone();
+        // Detached, so a slow service is abandoned once another has answered
+        thread::spawn(move || {
+            let _ = tx.send((index, service, fetch_ip(&client, service)));
+        });
+    }
+    drop(tx);
+
+    // Take the first valid answer, preferring earlier services among any that
+    // have already arrived
+    let mut best: Option<(usize, &str, IpAddr)> = None;
+    while let Ok((index, service, result)) = rx.recv() {
+        match result {
+            Ok(ip) => {
+                best = Some((index, service, ip));
+                break;
             }
+            Err(e) => debug!("Failed to get IP from {}: {}", service, e),
+        }
+    }
+    while let Ok((index, service, result)) = rx.try_recv() {
+        if let Ok(ip) = result
+            && best.is_some_and(|(best_index, _, _)| index < best_index)
+        {
+            best = Some((index, service, ip));
         }
     }
 
-    anyhow::bail!("Failed to detect public IP address from any service")
+    match best {
+        Some((_, service, ip)) => {
+            debug!("Detected public IP: {} (from {})", ip, service);
+            Ok(ip)
+        }
+        None => anyhow::bail!("Failed to detect public IP address from any service"),
+    }
+}
+
+/// Ask one service for our public IP
+fn fetch_ip(client: &reqwest::blocking::Client, service: &str) -> Result<IpAddr> {
+    let body = client.get(service).send()?.error_for_status()?.text()?;
+    body.trim()
+        .parse()
+        .with_context(|| format!("unexpected response {:?}", body.trim()))
 }
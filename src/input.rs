@@ -0,0 +1,540 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, info, warn};
+
+/// Hosts buffered between the reader and the ping workers
+const HOST_CHANNEL_CAPACITY: usize = 1024;
+
+/// Where to read the host list from
+#[derive(Debug, Clone)]
+pub enum HostSource {
+    Stdin,
+    File(PathBuf),
+    /// Hosts given as command-line arguments, each parsed like a line of a host file
+    Args(Vec<String>),
+}
+
+impl std::fmt::Display for HostSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostSource::Stdin => write!(f, "stdin"),
+            HostSource::File(path) => write!(f, "{:?}", path),
+            HostSource::Args(_) => write!(f, "the command line"),
+        }
+    }
+}
+
+/// A host to ping, as read from the input
+#[derive(Debug, Clone)]
+pub struct HostEntry {
+    pub host: String,
+    /// Port given as `host:port` (or `[v6]:port`), overriding --tcp for this host
+    pub port: Option<u16>,
+    /// Free-form text after the host, echoed back in per-host output
+    pub label: Option<String>,
+    /// Inventory tags (JSON input only), echoed back in per-host output
+    pub tags: Vec<String>,
+    /// Ping timeout for this host, overriding --timeout-secs
+    pub timeout: Option<Duration>,
+    /// How much this host counts in the --weighted statistics (1 if not given)
+    pub weight: Option<f64>,
+}
+
+/// How the host list is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    /// One host per line, optionally followed by a label
+    Lines,
+    /// A JSON array of objects with `host` and optional `port`, `label`, and `tags`
+    Json,
+}
+
+/// One element of a JSON host list
+#[derive(Debug, Deserialize)]
+struct JsonHost {
+    host: String,
+    port: Option<u16>,
+    label: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    timeout_secs: Option<f64>,
+    weight: Option<f64>,
+}
+
+/// What to do with a host list longer than --max-hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MaxHostsAction {
+    /// Fail without pinging anything
+    Error,
+    /// Ping the first hosts and warn about the rest
+    Truncate,
+}
+
+/// How the host list is read
+#[derive(Debug, Clone)]
+pub struct InputOptions {
+    pub format: InputFormat,
+    /// Maximum number of addresses a single CIDR block may expand to
+    pub max_cidr_hosts: usize,
+    /// Drop entries whose host (and port) was already seen
+    pub dedup: bool,
+    /// Maximum number of hosts in the whole list, after expansion and dedup
+    pub max_hosts: Option<usize>,
+    pub max_hosts_action: MaxHostsAction,
+}
+
+/// Read hosts on a blocking thread and send them down a bounded channel as they
+/// are parsed, so pinging can start before the whole input has been read. The
+/// handle resolves to the number of hosts sent.
+pub fn spawn_reader(
+    source: HostSource,
+    options: InputOptions,
+) -> (mpsc::Receiver<HostEntry>, JoinHandle<Result<usize>>) {
+    let (tx, rx) = mpsc::channel(HOST_CHANNEL_CAPACITY);
+    let handle = tokio::task::spawn_blocking(move || {
+        let count = match &source {
+            HostSource::Stdin => send_hosts(io::stdin().lock(), &options, &tx)?,
+            HostSource::File(path) => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open input file {:?}", path))?;
+                send_hosts(BufReader::new(file), &options, &tx)?
+            }
+            HostSource::Args(hosts) => {
+                let options = InputOptions {
+                    format: InputFormat::Lines,
+                    ..options
+                };
+                send_hosts(io::Cursor::new(hosts.join("\n")), &options, &tx)?
+            }
+        };
+        info!("Read {} hosts from {}", count, source);
+        Ok(count)
+    });
+    (rx, handle)
+}
+
+/// Parse the host list in the configured format and send each host, after
+/// CIDR expansion and deduplication, down `tx`. Past --max-hosts the rest are
+/// only counted; if that is an error, nothing is sent until the whole list is
+/// known to fit.
+fn send_hosts<R: BufRead>(
+    reader: R,
+    options: &InputOptions,
+    tx: &mpsc::Sender<HostEntry>,
+) -> Result<usize> {
+    let mut sender = EntrySender {
+        options,
+        tx,
+        seen: HashSet::new(),
+        duplicates: 0,
+        count: 0,
+        held: Vec::new(),
+        excess: 0,
+    };
+    match options.format {
+        InputFormat::Lines => send_lines(reader, &mut sender)?,
+        InputFormat::Json => send_json(reader, &mut sender)?,
+    }
+    if options.dedup {
+        info!("Dropped {} duplicate hosts", sender.duplicates);
+    }
+    if let Some(max_hosts) = options.max_hosts
+        && sender.excess > 0
+    {
+        let total = sender.count + sender.excess;
+        match options.max_hosts_action {
+            MaxHostsAction::Error => anyhow::bail!(
+                "host list has {} hosts, more than {} (raise --max-hosts)",
+                total,
+                max_hosts
+            ),
+            MaxHostsAction::Truncate => warn!(
+                "Host list has {} hosts, more than --max-hosts {}; only the first {} are pinged",
+                total, max_hosts, max_hosts
+            ),
+        }
+    }
+    for entry in sender.held {
+        if tx.blocking_send(entry).is_err() {
+            break;
+        }
+    }
+    Ok(sender.count)
+}
+
+/// Forwards parsed entries to the ping workers
+struct EntrySender<'a> {
+    options: &'a InputOptions,
+    tx: &'a mpsc::Sender<HostEntry>,
+    seen: HashSet<(String, Option<u16>)>,
+    duplicates: usize,
+    count: usize,
+    /// Hosts kept back until the list is known to fit in --max-hosts
+    held: Vec<HostEntry>,
+    /// Hosts past --max-hosts
+    excess: usize,
+}
+
+impl EntrySender<'_> {
+    /// Expand and send one entry. Returns false once the receiver is gone, so
+    /// nobody wants the rest.
+    fn send(&mut self, entry: HostEntry) -> Result<bool> {
+        for entry in expand_cidr(entry, self.options.max_cidr_hosts)? {
+            if self.options.dedup && !self.seen.insert((entry.host.clone(), entry.port)) {
+                self.duplicates += 1;
+                continue;
+            }
+            let Some(max_hosts) = self.options.max_hosts else {
+                if self.tx.blocking_send(entry).is_err() {
+                    return Ok(false);
+                }
+                self.count += 1;
+                continue;
+            };
+            if self.count >= max_hosts {
+                // Keep reading only to report how long the list is
+                self.excess += 1;
+                continue;
+            }
+            self.count += 1;
+            match self.options.max_hosts_action {
+                MaxHostsAction::Error => self.held.push(entry),
+                MaxHostsAction::Truncate => {
+                    if self.tx.blocking_send(entry).is_err() {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Read newline-separated hosts, one per line, optionally followed by a label.
+/// Everything after a `#` is a comment, lines starting with `;` are comments too,
+/// and lines left empty are skipped.
+fn send_lines<R: BufRead>(reader: R, sender: &mut EntrySender) -> Result<()> {
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.context("Failed to read host list")?;
+        let Some(entry) = parse_line(&line)
+            .with_context(|| format!("Failed to parse host list line {}", number + 1))?
+        else {
+            continue;
+        };
+        if !sender.send(entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read a JSON array of host objects. A `port` field takes precedence over one
+/// given in `host` itself.
+fn send_json<R: BufRead>(reader: R, sender: &mut EntrySender) -> Result<()> {
+    let hosts: Vec<JsonHost> =
+        serde_json::from_reader(reader).context("Failed to parse JSON host list")?;
+    for json in hosts {
+        let (host, port) = split_port(&json.host);
+        let context = || format!("Failed to parse host list entry {:?}", json.host);
+        let timeout = json
+            .timeout_secs
+            .map(timeout_from_secs)
+            .transpose()
+            .with_context(context)?;
+        let weight = json
+            .weight
+            .map(check_weight)
+            .transpose()
+            .with_context(context)?;
+        let entry = HostEntry {
+            host: host.to_string(),
+            port: json.port.or(port),
+            label: json.label,
+            tags: json.tags,
+            timeout,
+            weight,
+        };
+        if !sender.send(entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Parse one line into a host entry, or `None` for blank and comment lines. A
+/// `timeout=SECS` word after the host sets its timeout and a `weight=N` word its
+/// weight; the other words form the label.
+fn parse_line(line: &str) -> Result<Option<HostEntry>> {
+    let content = line.split('#').next().unwrap_or("").trim();
+    if content.starts_with(';') {
+        return Ok(None);
+    }
+    let mut words = content.split_whitespace();
+    let Some(host) = words.next() else {
+        return Ok(None);
+    };
+
+    let mut timeout = None;
+    let mut weight = None;
+    let mut label = Vec::new();
+    for word in words {
+        if let Some(secs) = word.strip_prefix("timeout=") {
+            timeout = Some(parse_timeout(secs)?);
+        } else if let Some(n) = word.strip_prefix("weight=") {
+            let n = n
+                .parse()
+                .with_context(|| format!("invalid weight {:?}", n))?;
+            weight = Some(check_weight(n)?);
+        } else {
+            label.push(word);
+        }
+    }
+
+    let (host, port) = split_port(host);
+    Ok(Some(HostEntry {
+        host: host.to_string(),
+        port,
+        label: (!label.is_empty()).then(|| label.join(" ")),
+        tags: Vec::new(),
+        timeout,
+        weight,
+    }))
+}
+
+/// Parse a per-host timeout in seconds
+fn parse_timeout(secs: &str) -> Result<Duration> {
+    let secs = secs
+        .parse()
+        .with_context(|| format!("invalid timeout {:?}", secs))?;
+    timeout_from_secs(secs)
+}
+
+fn timeout_from_secs(secs: f64) -> Result<Duration> {
+    if secs <= 0.0 || !secs.is_finite() {
+        anyhow::bail!("timeout must be a positive number of seconds, got {}", secs);
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+fn check_weight(weight: f64) -> Result<f64> {
+    if weight <= 0.0 || !weight.is_finite() {
+        anyhow::bail!("weight must be a positive number, got {}", weight);
+    }
+    Ok(weight)
+}
+
+/// Split a trailing `:port` off a host. IPv6 addresses need brackets to carry a
+/// port (`[::1]:443`); a bare `::1` is taken as a plain host.
+fn split_port(host: &str) -> (&str, Option<u16>) {
+    if let Some((addr, rest)) = host.strip_prefix('[').and_then(|h| h.split_once(']')) {
+        if rest.is_empty() {
+            return (addr, None);
+        }
+        if let Some(port) = rest.strip_prefix(':').and_then(|p| p.parse().ok()) {
+            return (addr, Some(port));
+        }
+    } else if let Some((name, port)) = host.rsplit_once(':')
+        && !name.contains(':')
+        && let Ok(port) = port.parse()
+    {
+        return (name, Some(port));
+    }
+    (host, None)
+}
+
+/// Expand a CIDR block (e.g. `192.168.1.0/24`) into the host addresses it
+/// contains, keeping the block's label. Other hosts are returned as-is. Fails if
+/// the block holds more than `limit` addresses.
+fn expand_cidr(entry: HostEntry, limit: usize) -> Result<Vec<HostEntry>> {
+    let Ok(net) = entry.host.parse::<IpNet>() else {
+        return Ok(vec![entry]);
+    };
+
+    let addrs: Vec<HostEntry> = net
+        .hosts()
+        .take(limit + 1)
+        .map(|ip| HostEntry {
+            host: ip.to_string(),
+            ..entry.clone()
+        })
+        .collect();
+    if addrs.len() > limit {
+        anyhow::bail!(
+            "CIDR block {} expands to more than {} addresses (raise --max-cidr-hosts)",
+            net,
+            limit
+        );
+    }
+    debug!("Expanded {} into {} addresses", net, addrs.len());
+    Ok(addrs)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A host with nothing but its name set
+    pub(crate) fn entry(host: &str) -> HostEntry {
+        HostEntry {
+            host: host.to_string(),
+            port: None,
+            label: None,
+            tags: Vec::new(),
+            timeout: None,
+            weight: None,
+        }
+    }
+
+    fn hosts(entries: &[HostEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.host.as_str()).collect()
+    }
+
+    #[test]
+    fn cidr_blocks_expand_to_their_hosts() {
+        let office = HostEntry {
+            label: Some("office".to_string()),
+            ..entry("192.168.1.0/30")
+        };
+        let expanded = expand_cidr(office, 1024).unwrap();
+        assert_eq!(hosts(&expanded), ["192.168.1.1", "192.168.1.2"]);
+        assert!(
+            expanded
+                .iter()
+                .all(|e| e.label.as_deref() == Some("office"))
+        );
+        let expanded = expand_cidr(entry("2001:db8::/127"), 1024).unwrap();
+        assert_eq!(hosts(&expanded), ["2001:db8::", "2001:db8::1"]);
+    }
+
+    #[test]
+    fn other_hosts_pass_through() {
+        for host in ["example.com", "192.0.2.1", "::1"] {
+            assert_eq!(hosts(&expand_cidr(entry(host), 1024).unwrap()), [host]);
+        }
+    }
+
+    #[test]
+    fn cidr_limit() {
+        // A /24 has 254 hosts
+        assert_eq!(expand_cidr(entry("10.0.0.0/24"), 254).unwrap().len(), 254);
+        let error = expand_cidr(entry("10.0.0.0/24"), 253).unwrap_err();
+        assert!(error.to_string().contains("more than 253 addresses"));
+        // Huge blocks fail without being expanded first
+        assert!(expand_cidr(entry("10.0.0.0/8"), 1024).is_err());
+    }
+
+    #[test]
+    fn ports_split_off_hosts() {
+        assert_eq!(split_port("example.com:443"), ("example.com", Some(443)));
+        assert_eq!(split_port("192.0.2.1:22"), ("192.0.2.1", Some(22)));
+        assert_eq!(split_port("[::1]:443"), ("::1", Some(443)));
+        assert_eq!(split_port("[::1]"), ("::1", None));
+    }
+
+    #[test]
+    fn hosts_without_ports() {
+        assert_eq!(split_port("::1"), ("::1", None));
+        assert_eq!(split_port("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(split_port("example.com"), ("example.com", None));
+        assert_eq!(split_port("example.com:http"), ("example.com:http", None));
+        assert_eq!(split_port("[::1]:99999"), ("[::1]:99999", None));
+    }
+
+    fn options() -> InputOptions {
+        InputOptions {
+            format: InputFormat::Lines,
+            max_cidr_hosts: 1024,
+            dedup: false,
+            max_hosts: None,
+            max_hosts_action: MaxHostsAction::Error,
+        }
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        for line in [
+            "",
+            "   ",
+            "\t",
+            "# a comment",
+            "  # indented",
+            "; also a comment",
+            "  ;x",
+        ] {
+            assert!(parse_line(line).unwrap().is_none(), "{:?}", line);
+        }
+        let entry = parse_line("example.com # trailing comment")
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.host, "example.com");
+        assert_eq!(entry.label, None);
+    }
+
+    #[test]
+    fn only_real_hosts_are_counted() {
+        let input = "# inventory\n\n192.0.2.1\n; retired\n   \nexample.com web\n# end\n";
+        let (tx, mut rx) = mpsc::channel(16);
+        let count = send_hosts(io::Cursor::new(input), &options(), &tx).unwrap();
+        drop(tx);
+        assert_eq!(count, 2);
+        let mut sent = Vec::new();
+        while let Ok(entry) = rx.try_recv() {
+            sent.push(entry.host);
+        }
+        assert_eq!(sent, ["192.0.2.1", "example.com"]);
+    }
+
+    #[test]
+    fn timeouts_and_labels() {
+        let entry = parse_line("db1.example.com timeout=0.5 primary db")
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.host, "db1.example.com");
+        assert_eq!(entry.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(entry.label.as_deref(), Some("primary db"));
+
+        let entry = parse_line("192.0.2.1").unwrap().unwrap();
+        assert_eq!((entry.timeout, entry.label), (None, None));
+    }
+
+    #[test]
+    fn invalid_timeouts() {
+        for line in [
+            "host timeout=",
+            "host timeout=abc",
+            "host timeout=0",
+            "host timeout=-1",
+        ] {
+            assert!(parse_line(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn weights() {
+        let entry = parse_line("core1 weight=4 timeout=2 backbone")
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.weight, Some(4.0));
+        assert_eq!(entry.timeout, Some(Duration::from_secs(2)));
+        assert_eq!(entry.label.as_deref(), Some("backbone"));
+        for line in [
+            "host weight=",
+            "host weight=x",
+            "host weight=0",
+            "host weight=-2",
+        ] {
+            assert!(parse_line(line).is_err(), "{:?}", line);
+        }
+    }
+}
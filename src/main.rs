@@ -1,78 +1,1334 @@
+mod compare;
+mod config;
+mod dns;
 mod geoip;
+mod input;
+mod output;
+mod pacing;
+mod random;
+mod socks;
+#[cfg(target_os = "linux")]
+mod timestamp;
+mod trace;
 
 use std::{
-    io::{self, BufRead},
-    net::ToSocketAddrs,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    io::{self, IsTerminal},
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    os::fd::BorrowedFd,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use clap::Parser;
-use geoip::{GeoIpClient, Location};
+use anyhow::{Context, Result};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use compare::{Baseline, Comparison};
+use dns::{DnsCache, Resolver};
+use futures_util::future::join_all;
+use geoip::{GeoIpClient, GeoIpOptions, GeoLevel, Geofence, Location};
+use input::{HostEntry, HostSource, InputFormat, InputOptions, MaxHostsAction};
+use output::{OutputFormat, Sink};
+use pacing::RateLimiter;
+use pnet_packet::{icmp::IcmpTypes, icmpv6::Icmpv6Types};
+use random::Randomness;
 use serde::{Deserialize, Serialize};
-use surge_ping::{Client, Config, PingIdentifier, PingSequence};
-use tokio::time::timeout;
+use socket2::SockRef;
+use surge_ping::{
+    Client, Config, ICMP, IcmpPacket, PingIdentifier, PingSequence, Pinger, SurgeError,
+};
+use tokio::{
+    net::{TcpSocket, lookup_host},
+    sync::{Semaphore, mpsc, watch},
+    time::{sleep, sleep_until, timeout},
+};
+use trace::Hop;
 use tracing::{debug, error, info, warn};
 
+/// Version of the JSON output shape, bumped whenever fields are renamed, removed,
+/// or change meaning
+const SCHEMA_VERSION: &str = "2";
+
+/// Upper bounds, in milliseconds, of the --histogram buckets (plus an overflow bucket)
+const HISTOGRAM_BOUNDS_MS: [i64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+/// Attempts made to resolve each host before giving up
+const DNS_ATTEMPTS: u32 = 3;
+/// Delay before the first DNS retry, doubled after each failure
+const DNS_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
 #[derive(Parser, Debug)]
 #[command(name = "rollping")]
 #[command(about = "Ping multiple hosts and aggregate statistics", long_about = None)]
 struct Args {
-    /// Number of pings to send to each host
+    /// Hosts to ping, instead of reading them from --input or stdin
+    #[arg(value_name = "HOST")]
+    hosts: Vec<String>,
+
+    /// Number of pings to send to each host, or 0 to keep pinging until
+    /// interrupted with Ctrl-C
     #[arg(short = 'c', long, default_value = "3")]
     count: usize,
 
+    /// Include a histogram of ping times in the output
+    #[arg(long = "histogram")]
+    histogram: bool,
+
+    /// Include a 95% confidence interval around the average ping time
+    #[arg(long = "confidence-interval")]
+    confidence_interval: bool,
+
+    /// Include outlier-resistant statistics (median absolute deviation)
+    #[arg(long = "robust-stats")]
+    robust_stats: bool,
+
+    /// Include an average ping time and packet loss that count each host by its
+    /// weight=N from the host list (1 if not given)
+    #[arg(long = "weighted")]
+    weighted: bool,
+
+    /// Include this machine's host name and the rollping version in the output,
+    /// to tell records collected from many machines apart
+    #[arg(long = "metadata")]
+    metadata: bool,
+
+    /// Count each lost ping (or, with --stats-source best, each host that never
+    /// replied) as this many milliseconds in the latency statistics
+    #[arg(long = "penalty-ms", value_name = "MS")]
+    penalty_ms: Option<f64>,
+
+    /// Like --penalty-ms, using the ping timeout as the penalty
+    #[arg(long = "penalty-timeout", conflicts_with = "penalty_ms")]
+    penalty_timeout: bool,
+
+    /// Compute latency statistics from each host's best time, or from every ping
+    #[arg(long = "stats-source", value_enum, default_value = "best")]
+    stats_source: StatsSource,
+
+    /// Extra pings sent to each host before measuring, whose results are
+    /// discarded
+    #[arg(long = "warmup", default_value = "0")]
+    warmup: usize,
+
+    /// Retry a failed ping up to this many times before counting it as lost
+    #[arg(long = "retries", default_value = "0")]
+    retries: usize,
+
+    /// Report each host's best time as the mean of its N fastest pings (fewer if
+    /// fewer got a reply), rather than the single fastest
+    #[arg(long = "best-n", value_name = "N", default_value = "1")]
+    best_n: NonZeroUsize,
+
     /// Timeout in seconds for each ping
     #[arg(short = 't', long, default_value = "2.0")]
     timeout_secs: f64,
 
+    /// Seconds to wait between pings to the same host (each host takes roughly
+    /// count * interval plus RTTs)
+    #[arg(short = 'I', long, default_value = "0.0")]
+    interval: f64,
+
+    /// Only resolve (and with --geo-targets, geolocate) hosts; send no pings.
+    /// Implies --per-host
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+
+    /// Time TCP connections to this port instead of sending ICMP pings
+    #[arg(long = "tcp")]
+    tcp: Option<u16>,
+
+    /// Make TCP connects through this SOCKS5 proxy (IP:PORT); needs --tcp
+    #[arg(long = "socks5", value_name = "IP:PORT", requires = "tcp")]
+    socks5: Option<SocketAddr>,
+
+    /// Size in bytes of the ICMP echo payload
+    #[arg(long = "payload-size", default_value = "8", value_parser = clap::value_parser!(u16).range(1..=65507))]
+    payload_size: u16,
+
+    /// Fill the ICMP echo payload with random bytes instead of zeros, for links
+    /// that compress or special-case all-zero packets
+    #[arg(long = "random-payload")]
+    random_payload: bool,
+
+    /// Seed the random ping identifiers and payloads, so a run can be reproduced
+    #[arg(long = "seed", value_name = "N")]
+    seed: Option<u64>,
+
+    /// IP TTL (IPv4) / hop limit (IPv6) of outgoing pings
+    #[arg(long = "ttl", value_parser = clap::value_parser!(u32).range(1..=255))]
+    ttl: Option<u32>,
+
+    /// Also trace the route to each host, adding a hop list to the per-host
+    /// results (needs root or CAP_NET_RAW)
+    #[arg(long = "traceroute")]
+    traceroute: bool,
+
+    /// Highest TTL probed by --traceroute
+    #[arg(long = "max-hops", default_value = "30", requires = "traceroute", value_parser = clap::value_parser!(u8).range(1..))]
+    max_hops: u8,
+
+    /// DSCP class (0-63) to mark outgoing pings with
+    #[arg(long = "dscp", value_parser = clap::value_parser!(u8).range(0..=63))]
+    dscp: Option<u8>,
+
+    /// Time pings with kernel (or NIC hardware) send and receive timestamps
+    /// instead of in userspace (Linux only)
+    #[arg(long = "hw-timestamps", conflicts_with = "tcp")]
+    hw_timestamps: bool,
+
     /// Increase logging verbosity (-v for WARN, -vv for INFO)
     #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Disable all logging, overriding -v (RUST_LOG still takes precedence)
+    #[arg(short = 'q', long = "quiet")]
+    quiet: bool,
+
     /// Enable geolocation (fetches and includes location data)
     #[arg(short = 'g', long = "geo")]
     geo: bool,
+
+    /// Geolocate each target host (included in --per-host output)
+    #[arg(long = "geo-targets")]
+    geo_targets: bool,
+
+    /// Only ping hosts geolocated to this country (ISO code, e.g. DE); others are
+    /// reported as skipped. Repeatable; implies --geo-targets
+    #[arg(long = "geo-only-country", value_name = "CODE")]
+    geo_only_country: Vec<String>,
+
+    /// With --geo-only-country, also skip hosts that cannot be geolocated
+    /// instead of pinging them
+    #[arg(long = "skip-ungeolocated", requires = "geo_only_country")]
+    skip_ungeolocated: bool,
+
+    /// Use this GeoIP database instead of downloading one (may be gzip-compressed)
+    #[arg(long = "geo-db")]
+    geo_db: Option<PathBuf>,
+
+    /// Use this ASN database instead of downloading one (may be gzip-compressed)
+    #[arg(long = "geo-asn-db")]
+    geo_asn_db: Option<PathBuf>,
+
+    /// Re-download the cached GeoIP database once it is older than this many days
+    #[arg(long = "geo-db-max-age-days", default_value = "30")]
+    geo_db_max_age_days: u64,
+
+    /// Geolocation detail: city (default) or country, which needs a much
+    /// smaller database
+    #[arg(long = "geo-level", value_enum, default_value = "city")]
+    geo_level: GeoLevel,
+
+    /// Never download or refresh GeoIP databases; geolocation is disabled if
+    /// they are not already cached
+    #[arg(long = "geo-offline")]
+    geo_offline: bool,
+
+    /// Give up on GeoIP downloads and public IP detection after this many
+    /// seconds without progress
+    #[arg(long = "geo-timeout-secs", default_value = "10")]
+    geo_timeout_secs: u64,
+
+    /// Include per-host results in the output
+    #[arg(long = "per-host")]
+    per_host: bool,
+
+    /// Record every measured ping, with its send time and RTT, in each host's
+    /// results. Implies --per-host
+    #[arg(long = "trace")]
+    trace: bool,
+
+    /// List only failed hosts in the per-host results: those that never replied or
+    /// didn't resolve, or with =PCT, also those losing more than PCT% of pings.
+    /// Implies --per-host; the aggregate statistics still cover every host
+    #[arg(long = "only-failures", value_name = "PCT", num_args = 0..=1, require_equals = true)]
+    only_failures: Option<Option<f64>>,
+
+    /// Order per-host results by this key (non-responsive hosts always come last)
+    #[arg(long = "sort", value_enum)]
+    sort: Option<SortKey>,
+
+    /// Reverse the --sort order
+    #[arg(long = "reverse", requires = "sort")]
+    reverse: bool,
+
+    /// Output format [default: table on a terminal, json otherwise]
+    #[arg(long = "output-format", value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// Indent the JSON output for reading by hand (JSON output only)
+    #[arg(long = "json-pretty", conflicts_with = "ndjson")]
+    json_pretty: bool,
+
+    /// Round fractional values in the output to this many decimal places
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u8).range(0..=15)
+    )]
+    precision: u8,
+
+    /// Stream one JSON line per host as it completes, then a summary line
+    #[arg(long = "ndjson", conflicts_with = "output_format")]
+    ndjson: bool,
+
+    /// Print just this statistics field as a bare number instead of the full
+    /// output, e.g. p95_ms or packet_loss_pct
+    #[arg(
+        long = "print-only",
+        value_name = "FIELD",
+        value_parser = parse_print_only,
+        conflicts_with_all = ["output_format", "json_pretty", "ndjson"]
+    )]
+    print_only: Option<String>,
+
+    /// Re-ping the host list every this many seconds, printing statistics each
+    /// cycle, until interrupted with Ctrl-C
+    #[arg(long = "watch", value_name = "SECONDS")]
+    watch: Option<f64>,
+
+    /// Weight of the newest cycle in the --watch moving average of the average
+    /// ping time, from 0 (exclusive) to 1
+    #[arg(
+        long = "ewma-alpha",
+        value_name = "ALPHA",
+        default_value = "0.3",
+        requires = "watch"
+    )]
+    ewma_alpha: f64,
+
+    /// Maximum number of hosts to ping at the same time
+    #[arg(long = "max-concurrency", default_value = "256")]
+    max_concurrency: NonZeroUsize,
+
+    /// Ping the hosts in random order (reproducible with --seed) instead of input
+    /// order, reading the whole list before starting
+    #[arg(long = "shuffle")]
+    shuffle: bool,
+
+    /// Ping the hosts in batches of this many, finishing each batch before
+    /// reading the next hosts (every batch's results are still kept for the
+    /// final statistics)
+    #[arg(long = "batch-size", value_name = "N")]
+    batch_size: Option<NonZeroUsize>,
+
+    /// Send at most this many pings (or TCP connects) per second across all hosts
+    #[arg(long = "pps", value_name = "N")]
+    pps: Option<NonZeroU32>,
+
+    /// Write the output to this file instead of stdout (truncating it)
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+
+    /// Append to the --output file instead of truncating it, one record per line
+    #[arg(long = "append", requires = "output")]
+    append: bool,
+
+    /// Write the output to the Unix socket listening at this path instead of
+    /// stdout, falling back to stdout if it is unavailable
+    #[arg(long = "socket", value_name = "PATH", conflicts_with = "output")]
+    socket: Option<PathBuf>,
+
+    /// Finish within this many seconds of starting, reporting hosts not done by
+    /// then as deadline_exceeded
+    #[arg(long = "deadline-secs", value_name = "SECS", conflicts_with = "watch")]
+    deadline_secs: Option<f64>,
+
+    /// Exit with a nonzero status if overall packet loss exceeds this percentage
+    #[arg(long = "fail-on-loss", value_name = "PCT")]
+    fail_on_loss: Option<f64>,
+
+    /// Count hosts whose best ping time exceeds this many milliseconds as slow
+    #[arg(long = "max-rtt-ms", value_name = "MS")]
+    max_rtt_ms: Option<f64>,
+
+    /// Exit with a nonzero status if any host is slower than --max-rtt-ms
+    #[arg(long = "fail-on-slow", requires = "max_rtt_ms")]
+    fail_on_slow: bool,
+
+    /// Add a 0-100 health_score rating p95 latency and packet loss against the
+    /// --sla-p95-ms and --sla-loss-pct targets
+    #[arg(long = "score")]
+    score: bool,
+
+    /// p95 latency target of --score, in milliseconds
+    #[arg(
+        long = "sla-p95-ms",
+        value_name = "MS",
+        default_value = "100",
+        requires = "score"
+    )]
+    sla_p95_ms: f64,
+
+    /// Packet loss target of --score, in percent
+    #[arg(
+        long = "sla-loss-pct",
+        value_name = "PCT",
+        default_value = "1",
+        requires = "score"
+    )]
+    sla_loss_pct: f64,
+
+    /// Compare this run with a previous run's JSON output, adding the changes
+    /// in latency and loss and the hosts that newly failed or recovered
+    #[arg(long = "compare", value_name = "BASELINE")]
+    compare: Option<PathBuf>,
+
+    /// Read hosts from a file instead of stdin
+    #[arg(short = 'i', long = "input")]
+    input: Option<PathBuf>,
+
+    /// Format of the host list: lines (default) or a JSON array of host objects
+    #[arg(long = "input-format", value_enum, default_value = "lines")]
+    input_format: InputFormat,
+
+    /// Maximum number of addresses a single CIDR block may expand to
+    #[arg(long = "max-cidr-hosts", default_value = "1024")]
+    max_cidr_hosts: usize,
+
+    /// Refuse a host list with more than this many hosts, after CIDR expansion
+    /// and --dedup
+    #[arg(long = "max-hosts", value_name = "N")]
+    max_hosts: Option<NonZeroUsize>,
+
+    /// What to do with a host list longer than --max-hosts: fail without
+    /// pinging, or ping only the first N hosts
+    #[arg(
+        long = "max-hosts-action",
+        value_enum,
+        default_value = "error",
+        requires = "max_hosts"
+    )]
+    max_hosts_action: MaxHostsAction,
+
+    /// Resolve host names by querying this DNS server (IP:PORT) instead of the
+    /// system resolver
+    #[arg(long = "resolver", value_name = "IP:PORT")]
+    resolver: Option<SocketAddr>,
+
+    /// Look up the reverse-DNS (PTR) name of each resolved address, through the
+    /// --resolver server or else the first nameserver in /etc/resolv.conf
+    #[arg(long = "ptr")]
+    ptr: bool,
+
+    /// Reuse a resolved address for this many seconds, across --watch cycles
+    /// too (0 disables the cache)
+    #[arg(long = "dns-cache-ttl", value_name = "SECS", default_value = "300")]
+    dns_cache_ttl: u64,
+
+    /// Send pings from this local address; only hosts of its address family are
+    /// pinged
+    #[arg(long = "source-address", value_name = "IP")]
+    source_address: Option<IpAddr>,
+
+    /// Send pings out of this network interface, e.g. eth1 (Linux only)
+    #[arg(long = "interface", value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Drop repeated hosts from the input, keeping the first
+    #[arg(long = "dedup")]
+    dedup: bool,
+
+    /// Ping each resolved address (and port) only once, keeping the first host
+    /// that resolved to it
+    #[arg(long = "dedup-resolved")]
+    dedup_resolved: bool,
+
+    /// Ping every address a hostname resolves to, each as its own result,
+    /// instead of only the first
+    #[arg(long = "all-addresses")]
+    all_addresses: bool,
+
+    /// Only ping IPv4 addresses
+    #[arg(short = '4', long = "ipv4", conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only ping IPv6 addresses
+    #[arg(short = '6', long = "ipv6")]
+    ipv6: bool,
+
+    /// Read default option values from this TOML file of `option = value`
+    /// lines; flags given on the command line override them
+    #[arg(long = "config", value_name = "PATH")]
+    config: Option<PathBuf>,
+}
+
+/// Which ping times feed the aggregate latency statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StatsSource {
+    /// Each host's best (minimum) time, one value per host
+    Best,
+    /// Every successful ping to every host
+    All,
+}
+
+/// Key used to order per-host results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SortKey {
+    /// Best ping time, fastest first
+    Latency,
+    /// Packet loss, lowest first
+    Loss,
+    /// Host name as given on input
+    Host,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpFamily::V4,
+            IpAddr::V6(_) => IpFamily::V6,
+        }
+    }
+
+    fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => ip.is_ipv4(),
+            IpFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6"),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Statistics {
-    /// Unix epoch timestamp when the measurement was taken
+    /// Version of the output shape (see SCHEMA_VERSION)
+    schema_version: String,
+    /// Unix epoch timestamp (seconds) when the run, or --watch cycle, started
     timestamp: u64,
+    /// Host name of the machine that ran rollping (only with --metadata)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_hostname: Option<String>,
+    /// Version of rollping that produced the record (only with --metadata)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rollping_version: Option<String>,
     /// Average ping time in microseconds
     avg_microsecs: i64,
-    /// Median ping time in microseconds
-    median_microsecs: i64,
+    /// How many ping times went into avg_microsecs; 0 when nothing replied
+    /// (not in the output)
+    #[serde(skip)]
+    avg_samples: usize,
+    /// Exponentially weighted moving average of avg_microsecs across cycles
+    /// (only with --watch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_ewma_microsecs: Option<i64>,
+    /// Lower bound of the 95% confidence interval of the average (only with --confidence-interval)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_ci_low_microsecs: Option<i64>,
+    /// Upper bound of the 95% confidence interval of the average (only with --confidence-interval)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_ci_high_microsecs: Option<i64>,
+    /// Geometric mean ping time in microseconds (less skewed by outliers than the average)
+    geomean_microsecs: i64,
+    /// Median of the hosts' best ping times in microseconds, whatever --stats-source says
+    median_best_microsecs: i64,
+    /// Median of every ping time in microseconds (only with --stats-source all)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    median_all_microsecs: Option<i64>,
     /// 95th percentile ping time in microseconds
     p95_microsecs: i64,
     /// 99th percentile ping time in microseconds
     p99_microsecs: i64,
+    /// Minimum ping time in microseconds
+    min_microsecs: i64,
     /// Maximum ping time in microseconds
     max_microsecs: i64,
+    /// Population standard deviation of ping times in microseconds
+    stddev_microsecs: i64,
+    /// Median absolute deviation of ping times from their median, in microseconds
+    /// (only with --robust-stats)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mad_microsecs: Option<i64>,
+    /// Mean absolute difference between consecutive pings, averaged across hosts, in microseconds
+    jitter_microsecs: i64,
+    /// Percentage of all pings, across every host, that got no reply
+    packet_loss_pct: f64,
+    /// Average ping time with each host's pings counted by its weight, in
+    /// microseconds (only with --weighted)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weighted_avg_microsecs: Option<i64>,
+    /// Packet loss with each host's pings counted by its weight (only with --weighted)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weighted_packet_loss_pct: Option<f64>,
     /// Number of hosts that failed to respond
     non_responsive_nodes: usize,
+    /// Number of hosts whose name could not be resolved (included in non_responsive_nodes)
+    unresolved_hosts: usize,
+    /// Hosts cut off by --deadline-secs, left out of the other statistics (only
+    /// with --deadline-secs)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deadline_exceeded_hosts: Option<usize>,
+    /// Hosts outside --geo-only-country, which were not pinged (only with
+    /// --geo-only-country)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_hosts: Option<usize>,
+    /// Hosts whose best ping time exceeds --max-rtt-ms (only with --max-rtt-ms)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slow_hosts: Option<usize>,
+    /// 0-100 rating of p95 latency and packet loss against their targets (only
+    /// with --score)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_score: Option<f64>,
+    /// Number of hosts that resolved to an IPv4 address
+    ipv4_hosts: usize,
+    /// Number of hosts that resolved to an IPv6 address
+    ipv6_hosts: usize,
     /// Total number of hosts tested
     total_hosts: usize,
     /// Number of pings sent to each host
     pings_per_host: usize,
+    /// Which ping times the latency fields are computed from
+    stats_source: StatsSource,
     /// Timeout in seconds for each ping
     timeout_secs: f64,
+    /// Wall-clock seconds for the whole run, including DNS and geolocation
+    total_duration_secs: f64,
+    /// Set when Ctrl-C cut a fixed --count run short, so only the pings completed
+    /// by then are counted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    interrupted: bool,
+    /// Count of ping times per latency bucket (only with --histogram)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: Option<Vec<Bucket>>,
     /// Geolocation of the current machine
     #[serde(skip_serializing_if = "Option::is_none")]
     location: Option<Location>,
+    /// Responsive hosts per country code (only with --geo-targets)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countries: Option<BTreeMap<String, usize>>,
+    /// Responsive hosts per "city, country code" (only with --geo-targets at the
+    /// city level)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cities: Option<BTreeMap<String, usize>>,
+    /// Responsive host with the highest best ping time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worst_host: Option<HostReport>,
+    /// Responsive host with the lowest best ping time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    best_host: Option<HostReport>,
+    /// Statistics over just the hosts carrying each tag (only when hosts have tags)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grouped: Option<BTreeMap<String, Statistics>>,
+    /// Differences from the --compare baseline (only with --compare)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<Comparison>,
+    /// Per-host results (only with --per-host)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts: Option<Vec<HostReport>>,
 }
 
-#[derive(Debug)]
+impl Statistics {
+    /// Record the wall-clock duration of the run, on every tag group too
+    fn set_duration(&mut self, secs: f64) {
+        self.total_duration_secs = secs;
+        for group in self.grouped.iter_mut().flat_map(|g| g.values_mut()) {
+            group.set_duration(secs);
+        }
+    }
+
+    /// Number of hosts that replied: those neither failed, cut off by
+    /// --deadline-secs, nor skipped by --geo-only-country
+    fn responsive_hosts(&self) -> usize {
+        self.total_hosts
+            .saturating_sub(self.non_responsive_nodes)
+            .saturating_sub(self.deadline_exceeded_hosts.unwrap_or(0))
+            .saturating_sub(self.skipped_hosts.unwrap_or(0))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bucket {
+    /// Upper bound of the bucket in microseconds, or null for the overflow bucket
+    le_microsecs: Option<i64>,
+    /// Number of ping times above the previous bucket's bound and at most this one
+    count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HostReport {
+    /// Host as given on input
+    host: String,
+    /// Label given after the host on its input line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    /// TCP port given with the host on its input line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    /// Tags given with the host in JSON input
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Resolved IP address that was pinged
+    ip: Option<IpAddr>,
+    /// PTR name of the resolved IP (only with --ptr, and if it has one)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reverse_dns: Option<String>,
+    /// Weight given with the host on its input line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<f64>,
+    /// Whether the host answered, and if not, why
+    status: HostStatus,
+    /// Best ping time in microseconds
+    best_time_microsecs: Option<i64>,
+    /// Number of pings that received a reply
+    successful_pings: usize,
+    /// Percentage of pings to this host that got no reply
+    packet_loss_pct: f64,
+    /// Whether the best ping time exceeds --max-rtt-ms (only with --max-rtt-ms)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exceeded_max_rtt: Option<bool>,
+    /// Geolocation of the resolved IP (only with --geo-targets)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<Location>,
+    /// Great-circle distance from the current machine (needs --geo and --geo-targets)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance_km: Option<f64>,
+    /// Round-trip distance divided by best ping time, in km per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    implied_speed_km_per_sec: Option<f64>,
+    /// Number of lost pings by why they failed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    error_kinds: BTreeMap<PingErrorKind, usize>,
+    /// Every measured ping, in the order sent (only with --trace)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<Vec<Sample>>,
+    /// Route to the host, one entry per TTL (only with --traceroute)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hops: Option<Vec<Hop>>,
+}
+
+impl HostReport {
+    /// A report with every optional field filled in, whose flattened fields are
+    /// all the CSV columns a host can have
+    fn csv_template() -> Self {
+        HostReport {
+            host: String::new(),
+            label: Some(String::new()),
+            port: Some(0),
+            tags: Vec::new(),
+            ip: Some(IpAddr::from([0, 0, 0, 0])),
+            reverse_dns: Some(String::new()),
+            weight: Some(0.0),
+            status: HostStatus::Responsive,
+            best_time_microsecs: Some(0),
+            successful_pings: 0,
+            packet_loss_pct: 0.0,
+            exceeded_max_rtt: Some(false),
+            location: Some(Location::default()),
+            distance_km: Some(0.0),
+            implied_speed_km_per_sec: Some(0.0),
+            error_kinds: PingErrorKind::ALL
+                .into_iter()
+                .map(|kind| (kind, 0))
+                .collect(),
+            samples: None,
+            hops: None,
+        }
+    }
+}
+
+/// One measured ping to a host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sample {
+    /// Ping number, counting from 1 (warm-up pings are not recorded)
+    seq: usize,
+    /// When the ping was sent, as an RFC 3339 UTC timestamp
+    sent_at: String,
+    /// Round-trip time in microseconds, or null if the ping was lost
+    rtt_microsecs: Option<i64>,
+    /// Why the ping was lost
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_kind: Option<PingErrorKind>,
+}
+
+/// Settings that control how each host is pinged
+#[derive(Clone)]
+struct PingOptions {
+    count: usize,
+    /// Pings sent before the measured ones, whose results are discarded
+    warmup: usize,
+    /// Extra attempts made before a measured ping counts as lost
+    retries: usize,
+    /// Number of fastest pings averaged into a host's best time
+    best_n: usize,
+    timeout: Duration,
+    interval: Duration,
+    payload_size: usize,
+    /// Fill ICMP payloads with random bytes instead of zeros
+    random_payload: bool,
+    /// Keep the send time and outcome of every measured ping (--trace)
+    trace: bool,
+    /// Source of ping identifiers and random payloads (seeded with --seed)
+    randomness: Arc<Randomness>,
+    /// IP TTL / hop limit of ICMP pings, or the OS default
+    ttl: Option<u32>,
+    /// Trace the route to each host up to this many hops
+    traceroute: Option<u8>,
+    /// Paces sends across every host (only with --pps)
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// DSCP class to mark ICMP pings with
+    dscp: Option<u8>,
+    /// Send each ICMP ping on its own socket, timed by kernel timestamps
+    hw_timestamps: bool,
+    /// Local address to send pings and TCP connects from
+    source_address: Option<IpAddr>,
+    /// Network interface to send pings, TCP connects, and traces out of
+    interface: Option<String>,
+    /// Time TCP connects to this port instead of ICMP pings
+    tcp_port: Option<u16>,
+    /// SOCKS5 proxy TCP connects go through
+    socks5: Option<SocketAddr>,
+    /// Resolve and geolocate only, without pinging
+    dry_run: bool,
+    /// Skip hosts that resolve to an address (and port) another host already has
+    dedup_resolved: bool,
+    /// Start the hosts in random order (--shuffle)
+    shuffle: bool,
+    /// Ping every address a name resolves to, not just the first
+    all_addresses: bool,
+    /// Set once pinging should stop, on Ctrl-C (not in --watch mode)
+    stop: Option<watch::Receiver<bool>>,
+    /// When hosts still pinging are cut off as deadline_exceeded
+    deadline: Option<Instant>,
+    family: Option<IpFamily>,
+    /// DNS server to resolve names with, instead of the system resolver
+    resolver: Option<Arc<Resolver>>,
+    /// DNS server to look up PTR names with (only with --ptr)
+    reverse_resolver: Option<Arc<Resolver>>,
+    /// Names already resolved, kept across --watch cycles
+    dns_cache: Arc<DnsCache>,
+    /// Used to geolocate each target (only with --geo-targets)
+    geoip: Option<Arc<GeoIpClient>>,
+    /// Countries targets may be pinged in (only with --geo-only-country)
+    geofence: Option<Arc<Geofence>>,
+}
+
+/// Settings that control which statistics are computed and reported
+struct StatsOptions {
+    source: StatsSource,
+    histogram: bool,
+    /// Report a 95% confidence interval around the average
+    confidence_interval: bool,
+    /// Report outlier-resistant metrics such as MAD
+    robust: bool,
+    /// Report the average and packet loss weighted by host
+    weighted: bool,
+    /// Latency counted for each lost ping or unresponsive host, instead of
+    /// leaving it out
+    penalty_microsecs: Option<f64>,
+    /// Best ping time above which a host counts as slow
+    max_rtt_microsecs: Option<f64>,
+    /// Targets the health score is computed against (only with --score)
+    sla: Option<Sla>,
+    /// Whether --deadline-secs is set
+    deadline: bool,
+    /// Whether --geo-only-country is set
+    geofence: bool,
+    /// Tally responsive hosts by country (and at the city level, by city)
+    geo_coverage: Option<GeoLevel>,
+    pings_per_host: usize,
+    timeout_secs: f64,
+}
+
+/// Latency and loss targets for --score
+#[derive(Clone, Copy)]
+struct Sla {
+    p95_microsecs: f64,
+    loss_pct: f64,
+}
+
+/// ICMP sockets shared by every ping task, one per address family
+#[derive(Default)]
+struct PingClients {
+    v4: Option<Client>,
+    v6: Option<Client>,
+    /// Sequence numbers are handed out globally so that two hosts resolving to
+    /// the same IP never wait on the same reply
+    sequence: AtomicU16,
+}
+
+impl PingClients {
+    fn new(options: &PingOptions) -> Self {
+        let build = |kind: ICMP| {
+            let mut config = Config::builder().kind(kind);
+            if let Some(ttl) = options.ttl {
+                config = config.ttl(ttl);
+            }
+            if let Some(source) = options.source_address {
+                config = config.bind(SocketAddr::new(source, 0));
+            }
+            if let Some(interface) = &options.interface {
+                config = config.interface(interface);
+            }
+            let client = Client::new(&config.build())?;
+            if let Some(dscp) = options.dscp {
+                set_dscp(&client, kind, dscp)?;
+            }
+            io::Result::Ok(client)
+        };
+        let create = |kind: ICMP| match build(kind) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Failed to create {:?} ping client: {}", kind, e);
+                None
+            }
+        };
+        // Only the family of the source address can be bound to it
+        let source_family = options.source_address.map(|ip| IpFamily::of(&ip));
+        PingClients {
+            v4: (source_family != Some(IpFamily::V6))
+                .then(|| create(ICMP::V4))
+                .flatten(),
+            v6: (source_family != Some(IpFamily::V4))
+                .then(|| create(ICMP::V6))
+                .flatten(),
+            sequence: AtomicU16::new(0),
+        }
+    }
+
+    fn for_ip(&self, ip: &IpAddr) -> Option<&Client> {
+        match ip {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    fn next_sequence(&self) -> PingSequence {
+        PingSequence(self.sequence.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Bind `socket` to a network interface (SO_BINDTODEVICE), for --interface
+#[cfg(target_os = "linux")]
+fn bind_device(socket: SockRef, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: SockRef, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+/// Mark every packet sent on the client's socket with `dscp`, which occupies the
+/// high six bits of the IPv4 ToS / IPv6 traffic class byte
+fn set_dscp(client: &Client, kind: ICMP, dscp: u8) -> io::Result<()> {
+    // SAFETY: the descriptor is owned by `client`, which outlives this borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(client.get_socket().get_native_sock()) };
+    let socket = SockRef::from(&fd);
+    let tos = u32::from(dscp) << 2;
+    match kind {
+        ICMP::V4 => socket.set_tos_v4(tos),
+        ICMP::V6 => socket.set_tclass_v6(tos),
+    }
+}
+
+/// Outcome of pinging a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HostStatus {
+    /// At least one ping got a reply
+    Responsive,
+    /// The host resolved but no ping got a reply
+    Timeout,
+    /// The host name could not be resolved
+    ResolutionFailed,
+    /// The host resolved and was not pinged (--dry-run)
+    Resolved,
+    /// The host had not finished its pings when --deadline-secs ran out
+    DeadlineExceeded,
+    /// The host was geolocated outside --geo-only-country and not pinged
+    Skipped,
+}
+
+/// Addresses (and ports) already taken by a host, for --dedup-resolved
+type ClaimedTargets = Mutex<HashSet<(IpAddr, Option<u16>)>>;
+
+/// How a single host is measured
+enum Probe {
+    /// Echo requests through one pinger, kept for all the pings to the address
+    Icmp(Pinger),
+    /// Kernel-timestamped echo requests with this identifier (--hw-timestamps)
+    Timestamped(u16),
+    Tcp(u16),
+}
+
+#[derive(Debug, Clone)]
 struct HostResult {
+    host: String,
+    label: Option<String>,
+    port: Option<u16>,
+    tags: Vec<String>,
+    weight: Option<f64>,
+    ip: Option<IpAddr>,
+    reverse_dns: Option<String>,
+    status: HostStatus,
     best_time_microsecs: Option<f64>,
+    successful_pings: usize,
+    attempted_pings: usize,
+    location: Option<Location>,
+    /// RTT of every successful ping, in the order they were sent
+    samples_microsecs: Vec<f64>,
+    /// Number of lost pings by why they failed
+    error_kinds: BTreeMap<PingErrorKind, usize>,
+    /// Every measured ping, lost ones included (only with --trace)
+    trace: Option<Vec<Sample>>,
+    hops: Option<Vec<Hop>>,
+}
+
+impl HostResult {
+    /// The result for `entry` before it is resolved or pinged
+    fn new(entry: &HostEntry, options: &PingOptions) -> Self {
+        HostResult {
+            host: entry.host.clone(),
+            label: entry.label.clone(),
+            port: entry.port,
+            tags: entry.tags.clone(),
+            weight: entry.weight,
+            ip: None,
+            reverse_dns: None,
+            status: HostStatus::Timeout,
+            best_time_microsecs: None,
+            successful_pings: 0,
+            attempted_pings: if options.dry_run { 0 } else { options.count },
+            location: None,
+            samples_microsecs: Vec::new(),
+            error_kinds: BTreeMap::new(),
+            trace: options.trace.then(Vec::new),
+            hops: None,
+        }
+    }
+
+    /// Mark the host as cut off by --deadline-secs, discarding any pings it
+    /// already finished so that only complete hosts feed the statistics
+    fn cut_off(&mut self) {
+        self.status = HostStatus::DeadlineExceeded;
+        self.best_time_microsecs = None;
+        self.successful_pings = 0;
+        self.attempted_pings = 0;
+        self.samples_microsecs.clear();
+        self.error_kinds.clear();
+        if let Some(trace) = &mut self.trace {
+            trace.clear();
+        }
+    }
+
+    /// Whether the host failed: no reply, no address, or (given a threshold) more
+    /// than `loss_threshold` percent of pings lost
+    fn is_failure(&self, loss_threshold: Option<f64>) -> bool {
+        match self.status {
+            HostStatus::Timeout | HostStatus::ResolutionFailed | HostStatus::DeadlineExceeded => {
+                true
+            }
+            HostStatus::Resolved | HostStatus::Skipped => false,
+            HostStatus::Responsive => loss_threshold.is_some_and(|threshold| {
+                loss_pct(self.attempted_pings, self.successful_pings) > threshold
+            }),
+        }
+    }
+
+    /// Whether the host replied, but no faster than `max_rtt_microsecs`
+    fn is_slow(&self, max_rtt_microsecs: f64) -> bool {
+        self.best_time_microsecs
+            .is_some_and(|best| best > max_rtt_microsecs)
+    }
+
+    /// Build the per-host report; `origin` is the current machine's location, if known
+    fn report(&self, origin: Option<&Location>, max_rtt_microsecs: Option<f64>) -> HostReport {
+        let distance_km = origin
+            .zip(self.location.as_ref())
+            .and_then(|(from, to)| geoip::haversine_km(from, to));
+        let implied_speed_km_per_sec = distance_km
+            .zip(self.best_time_microsecs)
+            .filter(|&(_, rtt)| rtt > 0.0)
+            .map(|(d, rtt)| (2.0 * d / (rtt / 1_000_000.0)).round());
+
+        HostReport {
+            host: self.host.clone(),
+            label: self.label.clone(),
+            port: self.port,
+            tags: self.tags.clone(),
+            ip: self.ip,
+            reverse_dns: self.reverse_dns.clone(),
+            weight: self.weight,
+            status: self.status,
+            best_time_microsecs: self.best_time_microsecs.map(|t| t.round() as i64),
+            successful_pings: self.successful_pings,
+            packet_loss_pct: loss_pct(self.attempted_pings, self.successful_pings),
+            exceeded_max_rtt: max_rtt_microsecs.map(|max_rtt| self.is_slow(max_rtt)),
+            location: self.location.clone(),
+            distance_km: distance_km.map(|d| (d * 10.0).round() / 10.0),
+            implied_speed_km_per_sec,
+            error_kinds: self.error_kinds.clone(),
+            samples: self.trace.clone(),
+            hops: self.hops.clone(),
+        }
+    }
+}
+
+/// What went wrong, in the JSON error report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    /// Invalid combination or value of flags
+    Usage,
+    /// Reading input or writing output failed
+    Io,
+    /// The host list could not be parsed
+    Parse,
+    /// A --fail-on-loss, --fail-on-slow, or no-response check failed after the output was written
+    Check,
+    Other,
+}
+
+/// An error whose kind is known where it is raised
+#[derive(Debug)]
+struct Failure {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Failure {
+    fn usage(message: impl Into<String>) -> Self {
+        Failure {
+            kind: ErrorKind::Usage,
+            message: message.into(),
+        }
+    }
+
+    fn check(message: impl Into<String>) -> Self {
+        Failure {
+            kind: ErrorKind::Check,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Why a single ping (or TCP connect) failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PingErrorKind {
+    /// No reply within the timeout
+    Timeout,
+    /// A router dropped the ping because its TTL (hop limit) ran out
+    TimeExceeded,
+    /// The host or network was reported unreachable, by a router or the local stack
+    DestinationUnreachable,
+    /// A firewall rejected the ping: ICMP administratively prohibited, or
+    /// refused by the local firewall
+    AdminProhibited,
+    /// The host answered a TCP connect with a reset
+    ConnectionRefused,
+    /// Some other ICMP message came back instead of an echo reply
+    UnexpectedReply,
+    /// The --socks5 proxy failed or refused the connection
+    ProxyFailed,
+    Other,
+}
+
+impl PingErrorKind {
+    const ALL: [PingErrorKind; 8] = [
+        PingErrorKind::Timeout,
+        PingErrorKind::TimeExceeded,
+        PingErrorKind::DestinationUnreachable,
+        PingErrorKind::AdminProhibited,
+        PingErrorKind::ConnectionRefused,
+        PingErrorKind::UnexpectedReply,
+        PingErrorKind::ProxyFailed,
+        PingErrorKind::Other,
+    ];
+}
+
+/// A failed ping whose kind is known where it is raised
+#[derive(Debug)]
+struct PingFailure {
+    kind: PingErrorKind,
+    message: String,
+}
+
+impl PingFailure {
+    fn new(kind: PingErrorKind, message: impl Into<String>) -> Self {
+        PingFailure {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn timeout() -> Self {
+        PingFailure::new(PingErrorKind::Timeout, "timed out")
+    }
+}
+
+impl std::fmt::Display for PingFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PingFailure {}
+
+/// Classify a failed ping by the first cause in its chain that says what it is
+fn ping_error_kind(error: &anyhow::Error) -> PingErrorKind {
+    for cause in error.chain() {
+        if let Some(failure) = cause.downcast_ref::<PingFailure>() {
+            return failure.kind;
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return io_error_kind(e);
+        }
+    }
+    PingErrorKind::Other
+}
+
+/// What a socket error says about why a ping failed
+fn io_error_kind(error: &io::Error) -> PingErrorKind {
+    match error.kind() {
+        io::ErrorKind::TimedOut => PingErrorKind::Timeout,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+            PingErrorKind::DestinationUnreachable
+        }
+        // A local firewall REJECT rule makes sends fail with EPERM
+        io::ErrorKind::PermissionDenied => PingErrorKind::AdminProhibited,
+        io::ErrorKind::ConnectionRefused => PingErrorKind::ConnectionRefused,
+        _ => PingErrorKind::Other,
+    }
+}
+
+/// Classify an error by the first cause in its chain that says what it is
+fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    for cause in error.chain() {
+        if let Some(failure) = cause.downcast_ref::<Failure>() {
+            return failure.kind;
+        }
+        if cause.is::<io::Error>() {
+            return ErrorKind::Io;
+        }
+        if cause.is::<serde_json::Error>() {
+            return ErrorKind::Parse;
+        }
+    }
+    ErrorKind::Other
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    let started = Instant::now();
+    let timestamp = unix_timestamp();
+    let args = match with_config(Args::command().get_matches()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let json_errors = matches!(
+        output_format(&args),
+        Ok(OutputFormat::Json | OutputFormat::JsonPretty)
+    );
+
+    match run(args, started, timestamp).await {
+        Ok(()) => ExitCode::SUCCESS,
+        // Whatever read the output has stopped, so there is no one to tell
+        Err(e) if output::is_broken_pipe(&e) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            let kind = error_kind(&e);
+            // A failed check comes after the statistics were printed, so another
+            // JSON document on stdout would only get in the way
+            if json_errors && kind != ErrorKind::Check {
+                let error = serde_json::json!({ "error": format!("{:#}", e), "kind": kind });
+                let _ = output::write_stdout(&error.to_string());
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Re-parse the command line with the --config file's options in front of it,
+/// less any the command line overrides, so flags given on the command line win
+/// over the file, which wins over the built-in defaults
+fn with_config(cli: ArgMatches) -> Result<Args> {
+    let args = Args::from_arg_matches(&cli).unwrap_or_else(|e| e.exit());
+    let Some(path) = &args.config else {
+        return Ok(args);
+    };
+    let command = Args::command();
+    let mut argv: Vec<OsString> = std::env::args_os().take(1).collect();
+    argv.extend(config::flag_args(path, &command, &cli)?);
+    argv.extend(std::env::args_os().skip(1));
+    let matches = command.get_matches_from(argv);
+    Ok(Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+fn parse_print_only(name: &str) -> Result<String, String> {
+    output::printable_field(name).map(|_| name.to_string())
+}
 
-    // Determine log level based on verbosity flag
+/// The statistics as printed: the --print-only field, or the whole output
+fn render(stats: &Statistics, args: &Args, format: OutputFormat) -> Result<String> {
+    match &args.print_only {
+        Some(field) => output::render_field(stats, field, args.precision),
+        None => output::render(stats, format, args.precision),
+    }
+}
+
+/// Pick the output format from the flags, defaulting to a table on a terminal
+fn output_format(args: &Args) -> Result<OutputFormat> {
+    Ok(match (args.output_format, args.json_pretty) {
+        (None | Some(OutputFormat::Json), true) => OutputFormat::JsonPretty,
+        (Some(_), true) => {
+            anyhow::bail!(Failure::usage(
+                "--json-pretty can only be used with JSON output"
+            ))
+        }
+        (Some(format), false) => format,
+        (None, false)
+            if io::stdout().is_terminal()
+                && !args.ndjson
+                && args.output.is_none()
+                && args.socket.is_none() =>
+        {
+            OutputFormat::Table
+        }
+        (None, false) => OutputFormat::Json,
+    })
+}
+
+async fn run(args: Args, started: Instant, timestamp: u64) -> Result<()> {
+    // Determine log level based on the quiet and verbosity flags
     let log_level = match args.verbose {
+        _ if args.quiet => "off",
         0 => "error",
         1 => "warn",
         _ => "info",
@@ -87,261 +1343,2142 @@ async fn main() -> Result<()> {
                 .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(log_level)),
         )
         .init();
+    let output_format = output_format(&args)?;
+    let baseline = args.compare.as_deref().map(Baseline::load).transpose()?;
+    if args
+        .max_rtt_ms
+        .is_some_and(|ms| ms <= 0.0 || !ms.is_finite())
+    {
+        anyhow::bail!(Failure::usage(
+            "--max-rtt-ms must be a positive number of milliseconds"
+        ));
+    }
+    if args.sla_p95_ms <= 0.0 || !args.sla_p95_ms.is_finite() {
+        anyhow::bail!(Failure::usage(
+            "--sla-p95-ms must be a positive number of milliseconds"
+        ));
+    }
+    if !(0.0..100.0).contains(&args.sla_loss_pct) {
+        anyhow::bail!(Failure::usage(
+            "--sla-loss-pct must be a percentage from 0 up to (not including) 100"
+        ));
+    }
+    if args.penalty_ms.is_some_and(|p| p < 0.0 || !p.is_finite()) {
+        anyhow::bail!(Failure::usage(
+            "--penalty-ms must be a non-negative number of milliseconds"
+        ));
+    }
+    if args
+        .watch
+        .is_some_and(|secs| secs <= 0.0 || !secs.is_finite())
+    {
+        anyhow::bail!(Failure::usage(
+            "--watch must be a positive number of seconds"
+        ));
+    }
+    if args.ewma_alpha.is_nan() || args.ewma_alpha <= 0.0 || args.ewma_alpha > 1.0 {
+        anyhow::bail!(Failure::usage("--ewma-alpha must be above 0 and at most 1"));
+    }
+
+    let sink = match (&args.output, &args.socket) {
+        (Some(path), _) => Sink::open(path, args.append)?,
+        (None, Some(path)) => Sink::connect(path),
+        (None, None) => Sink::default(),
+    };
+
     info!(
         "Starting rollping with {} pings per host, {}s timeout",
         args.count, args.timeout_secs
     );
 
-    // Initialize geolocation (only if --geo flag is set)
+    if let Some(code) = args
+        .geo_only_country
+        .iter()
+        .find(|code| code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()))
+    {
+        anyhow::bail!(Failure::usage(format!(
+            "--geo-only-country {:?} is not a two-letter ISO country code",
+            code
+        )));
+    }
+    let geo_targets = args.geo_targets || !args.geo_only_country.is_empty();
+
+    // Initialize geolocation (only if --geo or --geo-targets is set)
     // Use spawn_blocking to avoid blocking the async runtime
-    let location = if args.geo {
-        tokio::task::spawn_blocking(|| {
-            let geoip_client = GeoIpClient::new();
-            if geoip_client.is_available() {
-                match geoip::get_public_ip() {
-                    Ok(ip) => {
-                        info!("Detected public IP: {}", ip);
-                        let loc = geoip_client.lookup(ip);
-                        if let Some(ref l) = loc {
-                            info!(
-                                "Current location: {:?}, {:?}, {:?}",
-                                l.city.as_deref().unwrap_or("Unknown"),
-                                l.country.as_deref().unwrap_or("Unknown"),
-                                l.country_code.as_deref().unwrap_or("??")
-                            );
-                        }
-                        loc
+    let (geoip_client, location) = if args.geo || geo_targets {
+        if let Some(path) = args
+            .geo_db
+            .iter()
+            .chain(&args.geo_asn_db)
+            .find(|p| !p.exists())
+        {
+            anyhow::bail!(Failure::usage(format!(
+                "GeoIP database not found at {:?}",
+                path
+            )));
+        }
+        let geo_options = GeoIpOptions {
+            db_path: args.geo_db.clone(),
+            asn_db_path: args.geo_asn_db.clone(),
+            max_age: Duration::from_secs(args.geo_db_max_age_days.saturating_mul(24 * 60 * 60)),
+            level: args.geo_level,
+            offline: args.geo_offline,
+            timeout: Duration::from_secs(args.geo_timeout_secs),
+        };
+        let locate_self = args.geo;
+        tokio::task::spawn_blocking(move || {
+            let geoip_client = GeoIpClient::new(&geo_options);
+            if !geoip_client.is_available() {
+                return (None, None);
+            }
+            let location = if locate_self {
+                locate_public_ip(&geoip_client, geo_options.timeout)
+            } else {
+                None
+            };
+            (Some(Arc::new(geoip_client)), location)
+        })
+        .await
+        .unwrap_or((None, None))
+    } else {
+        (None, None)
+    };
+    // Without a database every host would count as ungeolocated, so fail
+    // rather than ping hosts the geofence may not allow
+    if !args.geo_only_country.is_empty() && geoip_client.is_none() {
+        anyhow::bail!("--geo-only-country needs a GeoIP database, but geolocation is unavailable");
+    }
+
+    // Stream hosts from the arguments, else the input file, else stdin
+    let source = match &args.input {
+        _ if !args.hosts.is_empty() => {
+            if let Some(path) = &args.input {
+                warn!("Hosts were given as arguments; ignoring --input {:?}", path);
+            }
+            HostSource::Args(args.hosts.clone())
+        }
+        Some(path) => {
+            if !io::stdin().is_terminal() {
+                warn!(
+                    "Both --input and stdin were provided; reading hosts from {:?}",
+                    path
+                );
+            }
+            HostSource::File(path.clone())
+        }
+        None => HostSource::Stdin,
+    };
+    let input_options = InputOptions {
+        format: args.input_format,
+        max_cidr_hosts: args.max_cidr_hosts,
+        dedup: args.dedup,
+        max_hosts: args.max_hosts.map(NonZeroUsize::get),
+        max_hosts_action: args.max_hosts_action,
+    };
+    let (mut hosts, mut reader) = input::spawn_reader(source, input_options);
+
+    // A source address can only reach targets of its own family
+    let family = if args.ipv4 {
+        Some(IpFamily::V4)
+    } else if args.ipv6 {
+        Some(IpFamily::V6)
+    } else {
+        args.source_address.map(|ip| IpFamily::of(&ip))
+    };
+    if let (Some(source), Some(family)) = (args.source_address, family)
+        && !family.matches(&source)
+    {
+        anyhow::bail!(Failure::usage(format!(
+            "--source-address {} is not an {} address",
+            source, family
+        )));
+    }
+    // Check the interface up front, since a ping client that fails to bind to
+    // it would only log a warning
+    if let Some(interface) = &args.interface {
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?;
+        if let Err(e) = bind_device(SockRef::from(&socket), interface) {
+            anyhow::bail!(Failure::usage(format!(
+                "Cannot use --interface {:?}: {}",
+                interface, e
+            )));
+        }
+    }
+    if args.hw_timestamps && !cfg!(target_os = "linux") {
+        warn!("--hw-timestamps is only supported on Linux; timing pings in userspace");
+    }
+
+    if args.count == 0 && args.watch.is_some() {
+        anyhow::bail!(Failure::usage("--count 0 cannot be used with --watch"));
+    }
+    if args.interval < 0.0 || !args.interval.is_finite() {
+        anyhow::bail!(Failure::usage(
+            "--interval must be a non-negative number of seconds"
+        ));
+    }
+    if args.timeout_secs < 0.0 || !args.timeout_secs.is_finite() {
+        anyhow::bail!(Failure::usage(
+            "--timeout-secs must be a non-negative number of seconds"
+        ));
+    }
+    // With --count 0, ping until Ctrl-C; without an --interval, pings go out a
+    // second apart rather than back to back
+    let mut interval = Duration::from_secs_f64(args.interval);
+    if args.count == 0 && interval.is_zero() {
+        interval = Duration::from_secs(1);
+    }
+    let deadline = match args.deadline_secs {
+        Some(secs) if secs <= 0.0 || !secs.is_finite() => {
+            anyhow::bail!(Failure::usage(
+                "--deadline-secs must be a positive number of seconds"
+            ));
+        }
+        Some(secs) => Some(started + Duration::from_secs_f64(secs)),
+        None => None,
+    };
+    // A deadline ends a --count 0 soak the way Ctrl-C does; otherwise hosts
+    // still going when it passes are reported as unfinished
+    let (soak_deadline, deadline) = if args.count == 0 {
+        (deadline, None)
+    } else {
+        (None, deadline)
+    };
+
+    // Ctrl-C stops pinging, and the pings completed so far are still reported.
+    // Watch mode instead just stops between cycles.
+    let stop = args.watch.is_none().then(|| {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                interrupted = tokio::signal::ctrl_c() => {
+                    if interrupted.is_err() {
+                        return;
                     }
-                    Err(e) => {
-                        warn!("Failed to detect public IP: {}", e);
-                        None
+                    info!("Interrupted, stopping pings");
+                }
+                _ = deadline_reached(soak_deadline) => info!("Deadline reached, stopping pings"),
+            }
+            let _ = tx.send(true);
+            // A second Ctrl-C gives up on the partial statistics
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+        rx
+    });
+
+    // Ping all hosts concurrently
+    let resolver = args.resolver.map(|server| Arc::new(Resolver::new(server)));
+    let options = PingOptions {
+        count: args.count,
+        warmup: args.warmup,
+        retries: args.retries,
+        best_n: args.best_n.get(),
+        timeout: Duration::from_secs_f64(args.timeout_secs),
+        interval,
+        payload_size: args.payload_size as usize,
+        random_payload: args.random_payload,
+        trace: args.trace,
+        randomness: Arc::new(Randomness::new(args.seed)),
+        ttl: args.ttl,
+        traceroute: args.traceroute.then_some(args.max_hops),
+        rate_limiter: args.pps.map(|pps| Arc::new(RateLimiter::new(pps))),
+        dscp: args.dscp,
+        hw_timestamps: args.hw_timestamps && cfg!(target_os = "linux"),
+        source_address: args.source_address,
+        interface: args.interface.clone(),
+        tcp_port: args.tcp,
+        socks5: args.socks5,
+        dry_run: args.dry_run,
+        dedup_resolved: args.dedup_resolved,
+        shuffle: args.shuffle,
+        all_addresses: args.all_addresses,
+        stop,
+        deadline,
+        family,
+        resolver: resolver.clone(),
+        reverse_resolver: if args.ptr {
+            Some(reverse_resolver(resolver)?)
+        } else {
+            None
+        },
+        dns_cache: Arc::new(DnsCache::new(Duration::from_secs(args.dns_cache_ttl))),
+        geoip: if geo_targets { geoip_client } else { None },
+        geofence: (!args.geo_only_country.is_empty()).then(|| {
+            Arc::new(Geofence::new(
+                &args.geo_only_country,
+                args.skip_ungeolocated,
+            ))
+        }),
+    };
+
+    let Some(watch) = args.watch else {
+        let measured = measure(
+            hosts,
+            timestamp,
+            &args,
+            &options,
+            location,
+            baseline.as_ref(),
+            &sink,
+        );
+        tokio::pin!(measured);
+        // A bad input line fails the run as soon as the reader gets to it,
+        // dropping the pings still going rather than finishing them for nothing
+        let (mut stats, read) = tokio::select! {
+            stats = &mut measured => (stats?, false),
+            read = &mut reader => {
+                read??;
+                (measured.await?, true)
+            }
+        };
+        let expired = options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        let stopped = is_stopped(&options.stop);
+        // Unless it already failed, the reader may be blocked on a terminal
+        // after a stop or deadline, so don't wait for it then
+        if !read && (reader.is_finished() || !(stopped || expired)) {
+            reader.await??;
+        }
+        if stopped {
+            stats.interrupted = args.count != 0;
+        }
+
+        stats.set_duration(elapsed_secs(started));
+        sink.write_line(render(&stats, &args, output_format)?)
+            .await?;
+
+        // Fail only after printing, so pipelines still capture the output
+        if !args.dry_run && stats.total_hosts > 0 && stats.responsive_hosts() == 0 {
+            anyhow::bail!(Failure::check("no hosts responded"));
+        }
+        if let Some(threshold) = args.fail_on_loss
+            && stats.packet_loss_pct > threshold
+        {
+            anyhow::bail!(Failure::check(format!(
+                "packet loss {}% exceeds --fail-on-loss {}%",
+                stats.packet_loss_pct, threshold
+            )));
+        }
+        if args.fail_on_slow
+            && let Some(slow_hosts @ 1..) = stats.slow_hosts
+        {
+            anyhow::bail!(Failure::check(format!(
+                "{} hosts slower than --max-rtt-ms {}",
+                slow_hosts,
+                args.max_rtt_ms.unwrap_or_default()
+            )));
+        }
+        return Ok(());
+    };
+
+    // Watch mode: read the host list once, then re-ping it every cycle
+    let mut entries = Vec::new();
+    while let Some(entry) = hosts.recv().await {
+        entries.push(entry);
+    }
+    reader.await??;
+
+    let period = Duration::from_secs_f64(watch);
+    let mut ewma: Option<f64> = None;
+    loop {
+        let cycle_started = Instant::now();
+        let timestamp = unix_timestamp();
+        let (tx, rx) = mpsc::channel(entries.len().max(1));
+        for entry in &entries {
+            tx.try_send(entry.clone())
+                .expect("channel holds every host");
+        }
+        drop(tx);
+
+        let cycle = measure(
+            rx,
+            timestamp,
+            &args,
+            &options,
+            location.clone(),
+            baseline.as_ref(),
+            &sink,
+        );
+        let mut stats = tokio::select! {
+            stats = cycle => stats?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        // A cycle where nothing replied has no average to fold in
+        if stats.avg_samples > 0 {
+            let avg = stats.avg_microsecs as f64;
+            ewma = Some(ewma.map_or(avg, |prev| {
+                args.ewma_alpha * avg + (1.0 - args.ewma_alpha) * prev
+            }));
+        }
+        stats.avg_ewma_microsecs = ewma.map(|v| v.round() as i64);
+        stats.set_duration(elapsed_secs(cycle_started));
+        sink.write_line(render(&stats, &args, output_format)?)
+            .await?;
+
+        tokio::select! {
+            _ = sleep_until((cycle_started + period).into()) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    info!("Interrupted, stopping watch");
+
+    Ok(())
+}
+
+/// Ping every host from `hosts` and aggregate the results, including per-host
+/// reports when asked for. In NDJSON mode each host's report is written to
+/// `sink` as soon as it completes.
+async fn measure(
+    hosts: mpsc::Receiver<HostEntry>,
+    timestamp: u64,
+    args: &Args,
+    options: &PingOptions,
+    location: Option<Location>,
+    baseline: Option<&Baseline>,
+    sink: &Sink,
+) -> Result<Statistics> {
+    let (completed, printer) = if args.ndjson {
+        let (tx, mut rx) = mpsc::unbounded_channel::<HostResult>();
+        let origin = location.clone();
+        let sink = sink.clone();
+        let precision = args.precision;
+        let max_rtt_microsecs = args.max_rtt_ms.map(|ms| ms * 1000.0);
+        let only_failures = args.only_failures;
+        let printer = tokio::spawn(async move {
+            while let Some(result) = rx.recv().await {
+                if only_failures.is_some_and(|threshold| !result.is_failure(threshold)) {
+                    continue;
+                }
+                let report = result.report(origin.as_ref(), max_rtt_microsecs);
+                let written = match output::to_json(&report, precision) {
+                    Ok(line) => sink.write_line(line).await,
+                    Err(e) => Err(e),
+                };
+                match written {
+                    Ok(()) => {}
+                    // Nobody is reading any more; the summary write reports it
+                    Err(e) if output::is_broken_pipe(&e) => break,
+                    Err(e) => error!("Failed to write result for {}: {:#}", result.host, e),
+                }
+            }
+        });
+        (Some(tx), Some(printer))
+    } else {
+        (None, None)
+    };
+
+    let mut results = ping_hosts(
+        hosts,
+        options,
+        args.max_concurrency.get(),
+        args.batch_size.map(NonZeroUsize::get),
+        completed,
+    )
+    .await;
+    if let Some(printer) = printer {
+        printer.await?;
+    }
+    if results.is_empty() {
+        warn!("No hosts provided");
+    }
+
+    // Calculate statistics
+    let stats_options = StatsOptions {
+        source: args.stats_source,
+        histogram: args.histogram,
+        confidence_interval: args.confidence_interval,
+        robust: args.robust_stats,
+        weighted: args.weighted,
+        penalty_microsecs: args.penalty_ms.map(|ms| ms * 1000.0).or(args
+            .penalty_timeout
+            .then_some(args.timeout_secs * 1_000_000.0)),
+        max_rtt_microsecs: args.max_rtt_ms.map(|ms| ms * 1000.0),
+        sla: args.score.then_some(Sla {
+            p95_microsecs: args.sla_p95_ms * 1000.0,
+            loss_pct: args.sla_loss_pct,
+        }),
+        deadline: args.deadline_secs.is_some() && args.count != 0,
+        geofence: !args.geo_only_country.is_empty(),
+        geo_coverage: (args.geo_targets || !args.geo_only_country.is_empty())
+            .then_some(args.geo_level),
+        pings_per_host: args.count,
+        timeout_secs: args.timeout_secs,
+    };
+    let mut stats = calculate_statistics(&results, &stats_options, timestamp, location);
+    if args.metadata {
+        stats.source_hostname = local_hostname();
+        stats.rollping_version = Some(env!("CARGO_PKG_VERSION").to_string());
+    }
+    if let Some(baseline) = baseline {
+        stats.comparison = Some(baseline.compare(&stats, &results));
+    }
+    let tags: BTreeMap<&str, Vec<HostResult>> =
+        results.iter().fold(BTreeMap::new(), |mut groups, result| {
+            for tag in &result.tags {
+                groups.entry(tag.as_str()).or_default().push(result.clone());
+            }
+            groups
+        });
+    if !tags.is_empty() {
+        stats.grouped = Some(
+            tags.into_iter()
+                .map(|(tag, group)| {
+                    let group_stats = calculate_statistics(&group, &stats_options, timestamp, None);
+                    (tag.to_string(), group_stats)
+                })
+                .collect(),
+        );
+    }
+    if (args.per_host || args.dry_run || args.trace || args.only_failures.is_some()) && !args.ndjson
+    {
+        if let Some(threshold) = args.only_failures {
+            results.retain(|r| r.is_failure(threshold));
+        }
+        if let Some(key) = args.sort {
+            sort_results(&mut results, key, args.reverse);
+        }
+        let origin = stats.location.as_ref();
+        let max_rtt_microsecs = stats_options.max_rtt_microsecs;
+        stats.hosts = Some(
+            results
+                .iter()
+                .map(|r| r.report(origin, max_rtt_microsecs))
+                .collect(),
+        );
+    }
+    info!(
+        "Completed pinging {} hosts, {} non-responsive",
+        stats.total_hosts, stats.non_responsive_nodes
+    );
+    Ok(stats)
+}
+
+/// The DNS server for --ptr lookups: --resolver if given, else the system's
+fn reverse_resolver(resolver: Option<Arc<Resolver>>) -> Result<Arc<Resolver>> {
+    match resolver {
+        Some(resolver) => Ok(resolver),
+        None => Ok(Arc::new(Resolver::from_system().map_err(|e| {
+            Failure::usage(format!(
+                "--ptr needs a DNS server ({:#}); pass one with --resolver",
+                e
+            ))
+        })?)),
+    }
+}
+
+fn locate_public_ip(geoip_client: &GeoIpClient, timeout: Duration) -> Option<Location> {
+    match geoip::get_public_ip(timeout) {
+        Ok(ip) => {
+            info!("Detected public IP: {}", ip);
+            let loc = geoip_client.lookup(ip);
+            if let Some(ref l) = loc {
+                info!(
+                    "Current location: {:?}, {:?}, {:?}",
+                    l.city.as_deref().unwrap_or("Unknown"),
+                    l.country.as_deref().unwrap_or("Unknown"),
+                    l.country_code.as_deref().unwrap_or("??")
+                );
+            }
+            loc
+        }
+        Err(e) => {
+            warn!("Failed to detect public IP: {}", e);
+            None
+        }
+    }
+}
+
+async fn ping_hosts(
+    mut hosts: mpsc::Receiver<HostEntry>,
+    options: &PingOptions,
+    max_concurrency: usize,
+    batch_size: Option<usize>,
+    completed: Option<mpsc::UnboundedSender<HostResult>>,
+) -> Vec<HostResult> {
+    // TCP mode and dry runs never touch ICMP sockets, so don't open any
+    let clients = Arc::new(
+        if options.tcp_port.is_some() || options.dry_run || options.hw_timestamps {
+            PingClients::default()
+        } else {
+            PingClients::new(options)
+        },
+    );
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
+    // Addresses already taken by a host, when collapsing hosts that resolve alike
+    let claimed = options
+        .dedup_resolved
+        .then(|| Arc::new(Mutex::new(HashSet::new())));
+    let mut handles = Vec::new();
+    // Once stopped, hosts still waiting to start are never pinged
+    let mut stop = options.stop.clone();
+    // Hosts that never got to start before the deadline
+    let mut cut_off = Vec::new();
+    let mut results = Vec::new();
+    let mut duplicates = 0;
+
+    if options.shuffle {
+        let mut entries = Vec::new();
+        loop {
+            tokio::select! {
+                entry = hosts.recv() => match entry {
+                    Some(entry) => entries.push(entry),
+                    None => break,
+                },
+                _ = stop_requested(&mut stop) => break,
+                _ = deadline_reached(options.deadline) => break,
+            }
+        }
+        options.randomness.shuffle(&mut entries);
+        debug!("Shuffled {} hosts", entries.len());
+        // Feed them back through a channel of their own, so they are started
+        // (or cut off) the same way as unshuffled hosts
+        let (sender, receiver) = mpsc::channel(entries.len().max(1));
+        for entry in entries {
+            sender.try_send(entry).expect("channel fits every host");
+        }
+        hosts = receiver;
+    }
+
+    loop {
+        let entry = tokio::select! {
+            entry = hosts.recv() => match entry {
+                Some(entry) => entry,
+                None => break,
+            },
+            _ = stop_requested(&mut stop) => break,
+            _ = deadline_reached(options.deadline) => break,
+        };
+        let options = options.clone();
+        let clients = clients.clone();
+        let claimed = claimed.clone();
+        let completed = completed.clone();
+        // Wait for a free slot so only max_concurrency hosts are in flight
+        let permit = tokio::select! {
+            permit = semaphore.clone().acquire_owned() => {
+                permit.expect("semaphore is never closed")
+            }
+            _ = stop_requested(&mut stop) => break,
+            _ = deadline_reached(options.deadline) => {
+                cut_off.push(entry);
+                break;
+            }
+        };
+        let handle = tokio::spawn(async move {
+            let results = ping_host(&entry, &options, &clients, claimed.as_deref()).await;
+            drop(permit);
+            if let Some(completed) = completed {
+                for result in results.iter().flatten() {
+                    // The receiver only goes away once we're done
+                    let _ = completed.send(result.clone());
+                }
+            }
+            results
+        });
+        handles.push(handle);
+        // A batch finishes, and its tasks and sockets are gone, before the
+        // next hosts are read
+        if batch_size.is_some_and(|size| handles.len() >= size) {
+            debug!("Waiting for a batch of {} hosts", handles.len());
+            duplicates += join_hosts(std::mem::take(&mut handles), &mut results).await;
+        }
+    }
+    if options
+        .deadline
+        .is_some_and(|deadline| Instant::now() >= deadline)
+    {
+        // Only hosts already read are reported; a slow reader may have more
+        while let Ok(entry) = hosts.try_recv() {
+            cut_off.push(entry);
+        }
+    }
+
+    duplicates += join_hosts(handles, &mut results).await;
+    for entry in cut_off {
+        let mut result = HostResult::new(&entry, options);
+        result.cut_off();
+        if let Some(completed) = &completed {
+            let _ = completed.send(result.clone());
+        }
+        results.push(result);
+    }
+    if options.dedup_resolved {
+        info!(
+            "Dropped {} hosts resolving to an address already pinged",
+            duplicates
+        );
+    }
+    if is_stopped(&stop) {
+        // Hosts interrupted before their first measured ping have nothing to report
+        let before = results.len();
+        results.retain(|r| !matches!(r.status, HostStatus::Timeout) || r.attempted_pings > 0);
+        info!(
+            "Interrupted, dropped {} hosts without a completed ping",
+            before - results.len()
+        );
+    }
+
+    results
+}
+
+/// Wait for ping tasks to finish, adding their results to `results`. Returns
+/// the number of addresses skipped as already pinged.
+async fn join_hosts(
+    handles: Vec<tokio::task::JoinHandle<Vec<Option<HostResult>>>>,
+    results: &mut Vec<HostResult>,
+) -> usize {
+    let mut duplicates = 0;
+    for handle in handles {
+        match handle.await {
+            Ok(host_results) => {
+                for result in host_results {
+                    match result {
+                        Some(result) => results.push(result),
+                        None => duplicates += 1,
                     }
                 }
-            } else {
+            }
+            Err(e) => {
+                error!("Task join error: {}", e);
+            }
+        }
+    }
+    duplicates
+}
+
+/// Resolve and ping one host: its first address, or with --all-addresses each
+/// one as a separate result. Addresses are `None`, without being pinged, if
+/// `claimed` is given and another host already resolved to the same address
+/// (and port).
+async fn ping_host(
+    entry: &HostEntry,
+    options: &PingOptions,
+    clients: &PingClients,
+    claimed: Option<&ClaimedTargets>,
+) -> Vec<Option<HostResult>> {
+    let host = entry.host.as_str();
+    debug!("Pinging host: {} ({} times)", host, options.count);
+
+    let mut result = HostResult::new(entry, options);
+    let mut stop = options.stop.clone();
+    let resolved = tokio::select! {
+        resolved = resolve_host(host, options) => resolved,
+        _ = stop_requested(&mut stop) => return Vec::new(),
+        _ = deadline_reached(options.deadline) => {
+            result.cut_off();
+            return vec![Some(result)];
+        }
+    };
+    let mut addrs = match resolved {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            warn!("Failed to resolve host {}: {:#}", host, e);
+            result.status = HostStatus::ResolutionFailed;
+            result.attempted_pings = 0;
+            return vec![Some(result)];
+        }
+    };
+    if !options.all_addresses {
+        addrs.truncate(1);
+    } else if addrs.len() > 1 {
+        info!("Host {} resolved to {} addresses", host, addrs.len());
+    }
+
+    join_all(addrs.into_iter().map(|ip| {
+        let result = result.clone();
+        async move {
+            // The PTR lookup runs alongside the pings rather than before them
+            let (reverse_dns, result) = tokio::join!(
+                reverse_lookup(ip, options),
+                ping_address(entry, ip, result, options, clients, claimed)
+            );
+            result.map(|result| HostResult {
+                reverse_dns,
+                ..result
+            })
+        }
+    }))
+    .await
+}
+
+/// Look up the PTR name of `ip` for --ptr. A slow PTR zone only costs the DNS
+/// query timeout, and is given up on at a stop or the deadline.
+async fn reverse_lookup(ip: IpAddr, options: &PingOptions) -> Option<String> {
+    let resolver = options.reverse_resolver.as_ref()?;
+    let mut stop = options.stop.clone();
+    tokio::select! {
+        name = resolver.reverse(ip) => match name {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("Reverse lookup of {} failed: {:#}", ip, e);
                 None
             }
-        })
-        .await
-        .unwrap_or(None)
-    } else {
-        None
-    };
+        },
+        _ = stop_requested(&mut stop) => None,
+        _ = deadline_reached(options.deadline) => None,
+    }
+}
+
+/// Ping one resolved address of a host, filling in `result`
+async fn ping_address(
+    entry: &HostEntry,
+    ip_addr: IpAddr,
+    mut result: HostResult,
+    options: &PingOptions,
+    clients: &PingClients,
+    claimed: Option<&ClaimedTargets>,
+) -> Option<HostResult> {
+    let host = entry.host.as_str();
+    let count = options.count;
+    if let Some(claimed) = claimed
+        && !claimed.lock().unwrap().insert((ip_addr, entry.port))
+    {
+        debug!("Skipping {}: {} is already being pinged", host, ip_addr);
+        return None;
+    }
+    result.ip = Some(ip_addr);
+    result.location = options.geoip.as_ref().and_then(|g| g.lookup(ip_addr));
+    if let Some(geofence) = &options.geofence
+        && !geofence.allows(result.location.as_ref())
+    {
+        info!(
+            "Skipping {} ({}): outside --geo-only-country",
+            host, ip_addr
+        );
+        result.status = HostStatus::Skipped;
+        result.attempted_pings = 0;
+        return Some(result);
+    }
+
+    if options.dry_run {
+        info!(
+            "Host {} resolved to {} (dry run, not pinging)",
+            host, ip_addr
+        );
+        result.status = HostStatus::Resolved;
+        return Some(result);
+    }
+
+    let ping_timeout = entry.timeout.unwrap_or(options.timeout);
+    // A port given with the host switches it to TCP, overriding --tcp
+    let mut probe = match entry.port.or(options.tcp_port) {
+        Some(port) => Probe::Tcp(port),
+        None if options.hw_timestamps => Probe::Timestamped(options.randomness.ident()),
+        None => match clients.for_ip(&ip_addr) {
+            Some(client) => {
+                let mut pinger = client
+                    .pinger(ip_addr, PingIdentifier(options.randomness.ident()))
+                    .await;
+                pinger.timeout(ping_timeout);
+                Probe::Icmp(pinger)
+            }
+            None => {
+                error!("No ping client available for {} ({})", host, ip_addr);
+                // Nothing was sent, so nothing counts as lost
+                result.attempted_pings = 0;
+                return Some(result);
+            }
+        },
+    };
+
+    // Every attempt, including retries, gets a fresh sequence number so a late
+    // reply to an earlier one is never mistaken for it. Each returns when it was
+    // sent along with its outcome.
+    let mut attempt = async || {
+        // Waiting for the rate limiter doesn't eat into the timeout
+        if let Some(rate_limiter) = &options.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        let sent_at = SystemTime::now();
+        let outcome = match &mut probe {
+            // The pinger enforces the timeout itself, so the reply it was waiting
+            // for is forgotten rather than left behind
+            Probe::Icmp(pinger) => {
+                let seq = clients.next_sequence();
+                let payload = ping_payload(options);
+                ping_once(pinger, seq, &payload).await
+            }
+            Probe::Timestamped(ident) => {
+                let seq = clients.next_sequence();
+                let payload = ping_payload(options);
+                timestamped_ping(ip_addr, *ident, seq.0, payload, ping_timeout, options).await
+            }
+            Probe::Tcp(port) => {
+                let connect = connect_once(ip_addr, *port, options);
+                match timeout(ping_timeout, connect).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(PingFailure::timeout().into()),
+                }
+            }
+        };
+        (sent_at, outcome)
+    };
+
+    let pings = if count == 0 {
+        usize::MAX
+    } else {
+        options.warmup + count
+    };
+    let mut stop = options.stop.clone();
+    let mut measured = 0;
+    let mut expired = false;
+    for i in 0..pings {
+        if is_stopped(&stop) {
+            break;
+        }
+        if i > 0 && !options.interval.is_zero() {
+            tokio::select! {
+                _ = sleep(options.interval) => {}
+                _ = stop_requested(&mut stop) => break,
+                _ = deadline_reached(options.deadline) => {
+                    expired = true;
+                    break;
+                }
+            }
+        }
+        let pinged = async {
+            let (mut sent_at, mut outcome) = attempt().await;
+            if i >= options.warmup {
+                for retry in 1..=options.retries {
+                    if outcome.is_ok() {
+                        break;
+                    }
+                    debug!(
+                        "Host {} ping #{} retry {}/{}",
+                        host,
+                        i - options.warmup + 1,
+                        retry,
+                        options.retries
+                    );
+                    (sent_at, outcome) = attempt().await;
+                }
+            }
+            (sent_at, outcome)
+        };
+        // A stop abandons the ping in flight rather than waiting out its timeout
+        let (sent_at, outcome) = tokio::select! {
+            pinged = pinged => pinged,
+            _ = stop_requested(&mut stop) => break,
+            _ = deadline_reached(options.deadline) => {
+                expired = true;
+                break;
+            }
+        };
+        // Warm-up pings only prime ARP and route caches; they are not measured
+        if i < options.warmup {
+            debug!("Host {} warm-up ping #{}: {:?}", host, i + 1, outcome);
+            continue;
+        }
+        let n = i - options.warmup + 1;
+        measured = n;
+        match &outcome {
+            Ok(rtt) => {
+                let rtt_microsecs = rtt.as_secs_f64() * 1_000_000.0;
+                debug!("Host {} ping #{}: {:.0}µs", host, n, rtt_microsecs);
+                result.samples_microsecs.push(rtt_microsecs);
+            }
+            Err(e) => {
+                warn!("Host {} ping #{} failed: {}", host, n, e);
+            }
+        }
+        let error_kind = outcome.as_ref().err().map(ping_error_kind);
+        if let Some(kind) = error_kind {
+            *result.error_kinds.entry(kind).or_default() += 1;
+        }
+        if let Some(trace) = &mut result.trace {
+            trace.push(Sample {
+                seq: n,
+                sent_at: rfc3339(sent_at),
+                rtt_microsecs: outcome.as_ref().ok().map(|rtt| microsecs(*rtt)),
+                error_kind,
+            });
+        }
+    }
 
-    // Read hosts from stdin
-    let hosts = read_hosts_from_stdin()?;
-    info!("Read {} hosts from stdin", hosts.len());
+    if expired {
+        warn!("Host {} did not finish before the deadline", host);
+        result.cut_off();
+        return Some(result);
+    }
+    result.attempted_pings = measured;
+    result.successful_pings = result.samples_microsecs.len();
+    result.best_time_microsecs = best_time(&result.samples_microsecs, options.best_n);
 
-    if hosts.is_empty() {
-        warn!("No hosts provided on stdin");
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let stats = Statistics {
-            timestamp,
-            avg_microsecs: 0,
-            median_microsecs: 0,
-            p95_microsecs: 0,
-            p99_microsecs: 0,
-            max_microsecs: 0,
-            non_responsive_nodes: 0,
-            total_hosts: 0,
-            pings_per_host: args.count,
-            timeout_secs: args.timeout_secs,
-            location: location.clone(),
+    if let Some(best) = result.best_time_microsecs {
+        result.status = HostStatus::Responsive;
+        info!(
+            "Host {} best time: {:.0}µs ({}/{} successful)",
+            host, best, result.successful_pings, measured
+        );
+    } else {
+        warn!("Host {} failed all pings", host);
+    }
+
+    if let Some(max_hops) = options.traceroute
+        && !is_stopped(&stop)
+    {
+        let (ident, timeout, payload_size, source, interface) = (
+            options.randomness.ident(),
+            ping_timeout,
+            options.payload_size,
+            options.source_address,
+            options.interface.clone(),
+        );
+        let trace = tokio::task::spawn_blocking(move || {
+            let interface = interface.as_deref();
+            trace::traceroute(
+                ip_addr,
+                ident,
+                max_hops,
+                timeout,
+                payload_size,
+                source,
+                interface,
+            )
+        });
+        // Past the deadline the trace is abandoned, leaving the host without hops
+        let trace = tokio::select! {
+            trace = trace => Some(trace),
+            _ = deadline_reached(options.deadline) => None,
         };
-        println!("{}", serde_json::to_string(&stats)?);
-        return Ok(());
+        match trace {
+            Some(Ok(Ok(hops))) => result.hops = Some(hops),
+            Some(Ok(Err(e))) => warn!("Failed to trace route to {}: {:#}", host, e),
+            Some(Err(e)) => error!("Traceroute task join error: {}", e),
+            None => warn!("Trace to {} did not finish before the deadline", host),
+        }
     }
 
-    // Ping all hosts concurrently
-    let timeout_duration = Duration::from_secs_f64(args.timeout_secs);
-    let results = ping_hosts(&hosts, args.count, timeout_duration).await;
+    Some(result)
+}
 
-    // Calculate statistics
-    let stats = calculate_statistics(&results, args.count, args.timeout_secs, location);
-    info!(
-        "Completed pinging {} hosts, {} non-responsive",
-        stats.total_hosts, stats.non_responsive_nodes
-    );
+/// Mean of the `n` fastest samples, or of all of them if there are fewer
+fn best_time(samples: &[f64], n: usize) -> Option<f64> {
+    let mut fastest = samples.to_vec();
+    fastest.sort_by(f64::total_cmp);
+    fastest.truncate(n);
+    (!fastest.is_empty()).then(|| fastest.iter().sum::<f64>() / fastest.len() as f64)
+}
 
-    // Output JSON to stdout
-    println!("{}", serde_json::to_string(&stats)?);
+/// Order results by `key` (reversed if asked), keeping hosts without a reply at the end
+fn sort_results(results: &mut [HostResult], key: SortKey, reverse: bool) {
+    results.sort_by(|a, b| {
+        let (a_best, b_best) = match (a.best_time_microsecs, b.best_time_microsecs) {
+            (Some(a_best), Some(b_best)) => (a_best, b_best),
+            (a_best, b_best) => return b_best.is_some().cmp(&a_best.is_some()),
+        };
+        let ordering = match key {
+            SortKey::Latency => a_best.total_cmp(&b_best),
+            SortKey::Loss => loss_pct(a.attempted_pings, a.successful_pings)
+                .total_cmp(&loss_pct(b.attempted_pings, b.successful_pings)),
+            SortKey::Host => a.host.cmp(&b.host),
+        };
+        if reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+}
 
-    Ok(())
+fn is_stopped(stop: &Option<watch::Receiver<bool>>) -> bool {
+    stop.as_ref().is_some_and(|rx| *rx.borrow())
 }
 
-fn read_hosts_from_stdin() -> Result<Vec<String>> {
-    let stdin = io::stdin();
-    let hosts: Vec<String> = stdin
-        .lock()
-        .lines()
-        .filter_map(|line| {
-            line.ok()
-                .map(|l| l.trim().to_string())
-                .filter(|l| !l.is_empty())
-        })
-        .collect();
-    Ok(hosts)
+/// Wait until `deadline`; never returns if it is `None`
+async fn deadline_reached(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
 }
 
-async fn ping_hosts(hosts: &[String], count: usize, timeout_duration: Duration) -> Vec<HostResult> {
-    let mut handles = Vec::new();
+/// Wait until a stop is requested; never returns if `stop` is `None`
+async fn stop_requested(stop: &mut Option<watch::Receiver<bool>>) {
+    match stop {
+        // An error means the sender is gone, which only happens after it sent
+        Some(rx) => {
+            let _ = rx.wait_for(|&stopped| stopped).await;
+        }
+        None => std::future::pending().await,
+    }
+}
 
-    for host in hosts {
-        let host = host.clone();
-        let handle = tokio::spawn(async move { ping_host(&host, count, timeout_duration).await });
-        handles.push(handle);
+/// Resolve a host to its addresses of the requested family, from the DNS cache
+/// if it was resolved recently
+async fn resolve_host(host: &str, options: &PingOptions) -> Result<Vec<IpAddr>> {
+    let family = options.family;
+    let addrs = match options.dns_cache.get(host) {
+        Some(addrs) => {
+            debug!("Using cached addresses for {}", host);
+            addrs
+        }
+        None => {
+            let addrs = lookup_with_retries(host, options.resolver.as_deref()).await?;
+            options.dns_cache.insert(host, &addrs);
+            addrs
+        }
+    };
+
+    // Restrict to the requested family if any, keeping the resolver's order
+    let mut seen = HashSet::new();
+    let addrs: Vec<IpAddr> = addrs
+        .into_iter()
+        .filter(|ip| family.is_none_or(|family| family.matches(ip)) && seen.insert(*ip))
+        .collect();
+    if addrs.is_empty() {
+        match family {
+            None => anyhow::bail!("no addresses returned"),
+            Some(family) => anyhow::bail!("no {} address", family),
+        }
     }
+    Ok(addrs)
+}
 
-    let mut results = Vec::new();
-    for handle in handles {
-        match handle.await {
-            Ok(result) => results.push(result),
-            Err(e) => {
-                error!("Task join error: {}", e);
+/// Look up a host, retrying transient failures with exponential backoff
+async fn lookup_with_retries(host: &str, resolver: Option<&Resolver>) -> Result<Vec<IpAddr>> {
+    let mut backoff = DNS_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let lookup = match resolver {
+            Some(resolver) => resolver.lookup(host).await,
+            None => lookup_host(format!("{}:0", host))
+                .await
+                .map(|addrs| addrs.map(|a| a.ip()).collect())
+                .map_err(anyhow::Error::from),
+        };
+        match lookup {
+            Ok(addrs) => return Ok(addrs),
+            Err(e) if attempt < DNS_ATTEMPTS => {
+                debug!(
+                    "Resolving {} failed (attempt {}/{}): {:#}",
+                    host, attempt, DNS_ATTEMPTS, e
+                );
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
             }
+            Err(e) => return Err(e).context("DNS lookup failed"),
         }
     }
+}
 
-    results
+/// The ICMP echo payload for one ping: zeros, or random bytes with --random-payload
+fn ping_payload(options: &PingOptions) -> Vec<u8> {
+    let mut payload = vec![0u8; options.payload_size];
+    if options.random_payload {
+        options.randomness.fill(&mut payload);
+    }
+    payload
 }
 
-async fn ping_host(host: &str, count: usize, timeout_duration: Duration) -> HostResult {
-    debug!("Pinging host: {} ({} times)", host, count);
+async fn ping_once(pinger: &mut Pinger, seq: PingSequence, payload: &[u8]) -> Result<Duration> {
+    let (packet, duration) = pinger.ping(seq, payload).await.map_err(|e| match e {
+        SurgeError::Timeout { .. } => PingFailure::timeout(),
+        SurgeError::IOError(e) => {
+            PingFailure::new(io_error_kind(&e), format!("Ping failed: io error: {}", e))
+        }
+        e => PingFailure::new(PingErrorKind::Other, format!("Ping failed: {}", e)),
+    })?;
 
-    let config = Config::default();
-    let client = match Client::new(&config) {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Failed to create ping client for {}: {}", host, e);
-            return HostResult {
-                best_time_microsecs: None,
+    // Anything other than an echo reply (e.g. time exceeded with --ttl) is not a success
+    let failure = match packet {
+        IcmpPacket::V4(p) if p.get_icmp_type() == IcmpTypes::TimeExceeded => PingFailure::new(
+            PingErrorKind::TimeExceeded,
+            format!("TTL exceeded in transit at {}", p.get_source()),
+        ),
+        IcmpPacket::V6(p) if p.get_icmpv6_type() == Icmpv6Types::TimeExceeded => PingFailure::new(
+            PingErrorKind::TimeExceeded,
+            format!("Hop limit exceeded in transit at {}", p.get_source()),
+        ),
+        IcmpPacket::V4(p) if p.get_icmp_type() == IcmpTypes::DestinationUnreachable => {
+            // Codes 9, 10, and 13 are the administratively prohibited ones
+            let kind = match p.get_icmp_code().0 {
+                9 | 10 | 13 => PingErrorKind::AdminProhibited,
+                _ => PingErrorKind::DestinationUnreachable,
             };
+            PingFailure::new(
+                kind,
+                format!(
+                    "Destination unreachable (code {}) from {}",
+                    p.get_icmp_code().0,
+                    p.get_source()
+                ),
+            )
         }
+        IcmpPacket::V6(p) if p.get_icmpv6_type() == Icmpv6Types::DestinationUnreachable => {
+            // Code 1 is communication administratively prohibited
+            let kind = match p.get_icmpv6_code().0 {
+                1 => PingErrorKind::AdminProhibited,
+                _ => PingErrorKind::DestinationUnreachable,
+            };
+            PingFailure::new(
+                kind,
+                format!(
+                    "Destination unreachable (code {}) from {}",
+                    p.get_icmpv6_code().0,
+                    p.get_source()
+                ),
+            )
+        }
+        IcmpPacket::V4(p) if p.get_icmp_type() != IcmpTypes::EchoReply => PingFailure::new(
+            PingErrorKind::UnexpectedReply,
+            format!(
+                "Unexpected ICMP type {} from {}",
+                p.get_icmp_type().0,
+                p.get_source()
+            ),
+        ),
+        IcmpPacket::V6(p) if p.get_icmpv6_type() != Icmpv6Types::EchoReply => PingFailure::new(
+            PingErrorKind::UnexpectedReply,
+            format!(
+                "Unexpected ICMPv6 type {} from {}",
+                p.get_icmpv6_type().0,
+                p.get_source()
+            ),
+        ),
+        _ => return Ok(duration),
     };
+    Err(failure.into())
+}
 
-    let mut min_time_microsecs: Option<f64> = None;
-    let mut successful_pings = 0;
-
-    for i in 0..count {
-        match timeout(timeout_duration, ping_once(&client, host, i as u16)).await {
-            Ok(Ok(rtt)) => {
-                let rtt_microsecs = rtt.as_secs_f64() * 1_000_000.0;
-                debug!("Host {} ping #{}: {:.0}µs", host, i + 1, rtt_microsecs);
-                min_time_microsecs =
-                    Some(min_time_microsecs.map_or(rtt_microsecs, |min| min.min(rtt_microsecs)));
-                successful_pings += 1;
-            }
-            Ok(Err(e)) => {
-                warn!("Host {} ping #{} failed: {}", host, i + 1, e);
-            }
-            Err(_) => {
-                warn!("Host {} ping #{} timed out", host, i + 1);
-            }
-        }
-    }
+/// Ping once on a socket of its own, timed by the kernel's send and receive
+/// timestamps (--hw-timestamps). A ping the kernel gave no timestamps for is
+/// timed in userspace instead, with a warning the first time.
+#[cfg(target_os = "linux")]
+async fn timestamped_ping(
+    ip_addr: IpAddr,
+    ident: u16,
+    seq: u16,
+    payload: Vec<u8>,
+    ping_timeout: Duration,
+    options: &PingOptions,
+) -> Result<Duration> {
+    /// Set once a ping has had to be timed in userspace
+    static USERSPACE_TIMING_WARNED: AtomicBool = AtomicBool::new(false);
 
-    if let Some(best) = min_time_microsecs {
-        info!(
-            "Host {} best time: {:.0}µs ({}/{} successful)",
-            host, best, successful_pings, count
+    let (ttl, dscp, source, interface) = (
+        options.ttl,
+        options.dscp,
+        options.source_address,
+        options.interface.clone(),
+    );
+    let (rtt, clock) = tokio::task::spawn_blocking(move || {
+        let options = timestamp::SocketOptions {
+            ttl,
+            dscp,
+            source,
+            interface: interface.as_deref(),
+        };
+        timestamp::ping(ip_addr, ident, seq, &payload, ping_timeout, &options)
+    })
+    .await??;
+    debug!("Ping to {} timed with the {:?} clock", ip_addr, clock);
+    if clock == timestamp::Clock::Userspace
+        && !USERSPACE_TIMING_WARNED.swap(true, Ordering::Relaxed)
+    {
+        warn!(
+            "The kernel reported no timestamps for a ping to {}; timing it in userspace",
+            ip_addr
         );
-    } else {
-        warn!("Host {} failed all pings", host);
-    }
-
-    HostResult {
-        best_time_microsecs: min_time_microsecs,
     }
+    Ok(rtt)
 }
 
-async fn ping_once(client: &Client, host: &str, seq: u16) -> Result<Duration> {
-    // Resolve hostname to IP address
-    let ip_addr = format!("{}:0", host)
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Failed to resolve host: {}", host))?
-        .ip();
+#[cfg(not(target_os = "linux"))]
+async fn timestamped_ping(
+    _ip_addr: IpAddr,
+    _ident: u16,
+    _seq: u16,
+    _payload: Vec<u8>,
+    _ping_timeout: Duration,
+    _options: &PingOptions,
+) -> Result<Duration> {
+    anyhow::bail!("--hw-timestamps is only supported on Linux")
+}
 
-    let mut pinger = client.pinger(ip_addr, PingIdentifier(rand::random())).await;
+/// Time how long it takes to establish a TCP connection. Through a --socks5
+/// proxy, that is the connection to the proxy plus the proxy's own connect.
+async fn connect_once(ip_addr: IpAddr, port: u16, options: &PingOptions) -> Result<Duration> {
+    let target = SocketAddr::new(ip_addr, port);
+    // With a proxy, the local socket only ever talks to the proxy
+    let peer = options.socks5.unwrap_or(target);
+    let socket = match peer {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(interface) = &options.interface {
+        bind_device(SockRef::from(&socket), interface)
+            .with_context(|| format!("Failed to bind to interface {}", interface))?;
+    }
+    if let Some(source) = options.source_address {
+        socket
+            .bind(SocketAddr::new(source, 0))
+            .with_context(|| format!("Failed to bind to {}", source))?;
+    }
 
-    let payload = [0; 8];
-    let (_packet, duration) = pinger
-        .ping(PingSequence(seq), &payload)
+    let start = Instant::now();
+    let mut stream = socket
+        .connect(peer)
         .await
-        .map_err(|e| anyhow::anyhow!("Ping failed: {}", e))?;
+        .map_err(|e| PingFailure::new(io_error_kind(&e), format!("Connect failed: {}", e)))?;
+    if options.socks5.is_some() {
+        socks::connect(&mut stream, target).await.map_err(|e| {
+            PingFailure::new(
+                PingErrorKind::ProxyFailed,
+                format!("Connect through SOCKS5 proxy failed: {:#}", e),
+            )
+        })?;
+    }
+    Ok(start.elapsed())
+}
 
-    Ok(duration)
+/// The ping times statistics are computed from: each host's best time, or every
+/// successful ping. With a penalty, each failure (host or ping) adds it as a time.
+fn latency_samples(
+    results: &[HostResult],
+    source: StatsSource,
+    penalty_microsecs: Option<f64>,
+) -> Vec<f64> {
+    let mut times: Vec<f64> = match source {
+        StatsSource::Best => results
+            .iter()
+            .filter_map(|r| r.best_time_microsecs)
+            .collect(),
+        StatsSource::All => results
+            .iter()
+            .flat_map(|r| r.samples_microsecs.iter().copied())
+            .collect(),
+    };
+    // Fold failures in at the penalty latency, so they weigh on every metric
+    if let Some(penalty) = penalty_microsecs {
+        let failures: usize = match source {
+            StatsSource::Best => results
+                .iter()
+                .filter(|r| matches!(r.status, HostStatus::Timeout | HostStatus::ResolutionFailed))
+                .count(),
+            StatsSource::All => results
+                .iter()
+                .map(|r| r.attempted_pings - r.successful_pings)
+                .sum(),
+        };
+        times.extend(std::iter::repeat_n(penalty, failures));
+    }
+    times
+}
+
+/// Average latency (0 if nothing replied) and packet loss with each host's
+/// samples and pings counted `weight` times
+fn weighted_stats(
+    results: &[HostResult],
+    source: StatsSource,
+    penalty_microsecs: Option<f64>,
+) -> (f64, f64) {
+    let (mut latency, mut samples) = (0.0, 0.0);
+    let (mut lost, mut attempted) = (0.0, 0.0);
+    for r in results {
+        let weight = r.weight.unwrap_or(1.0);
+        let times = latency_samples(std::slice::from_ref(r), source, penalty_microsecs);
+        latency += weight * times.iter().sum::<f64>();
+        samples += weight * times.len() as f64;
+        lost += weight * (r.attempted_pings - r.successful_pings) as f64;
+        attempted += weight * r.attempted_pings as f64;
+    }
+    let avg = if samples > 0.0 {
+        latency / samples
+    } else {
+        0.0
+    };
+    let loss = if attempted > 0.0 {
+        (lost / attempted * 10_000.0).round() / 100.0
+    } else {
+        0.0
+    };
+    (avg, loss)
 }
 
 fn calculate_statistics(
     results: &[HostResult],
-    pings_per_host: usize,
-    timeout_secs: f64,
+    options: &StatsOptions,
+    timestamp: u64,
     location: Option<Location>,
 ) -> Statistics {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    let mut successful_times: Vec<f64> = results
-        .iter()
-        .filter_map(|r| r.best_time_microsecs)
-        .collect();
+    let mut successful_times = latency_samples(results, options.source, options.penalty_microsecs);
+    // The best-time median is reported even when the other fields use every ping
+    let best_times = match options.source {
+        StatsSource::Best => None,
+        StatsSource::All => Some(latency_samples(
+            results,
+            StatsSource::Best,
+            options.penalty_microsecs,
+        )),
+    };
 
     let non_responsive_nodes = results
         .iter()
-        .filter(|r| r.best_time_microsecs.is_none())
+        .filter(|r| matches!(r.status, HostStatus::Timeout | HostStatus::ResolutionFailed))
+        .count();
+    let unresolved_hosts = results
+        .iter()
+        .filter(|r| r.status == HostStatus::ResolutionFailed)
+        .count();
+    let deadline_exceeded_hosts = options.deadline.then(|| {
+        results
+            .iter()
+            .filter(|r| r.status == HostStatus::DeadlineExceeded)
+            .count()
+    });
+    let skipped_hosts = options.geofence.then(|| {
+        results
+            .iter()
+            .filter(|r| r.status == HostStatus::Skipped)
+            .count()
+    });
+    let slow_hosts = options
+        .max_rtt_microsecs
+        .map(|max_rtt| results.iter().filter(|r| r.is_slow(max_rtt)).count());
+    let ipv4_hosts = results
+        .iter()
+        .filter(|r| r.ip.is_some_and(|ip| ip.is_ipv4()))
+        .count();
+    let ipv6_hosts = results
+        .iter()
+        .filter(|r| r.ip.is_some_and(|ip| ip.is_ipv6()))
         .count();
     let total_hosts = results.len();
 
+    let attempted_pings: usize = results.iter().map(|r| r.attempted_pings).sum();
+    let successful_pings: usize = results.iter().map(|r| r.successful_pings).sum();
+    let packet_loss_pct = loss_pct(attempted_pings, successful_pings);
+    let (weighted_avg_microsecs, weighted_packet_loss_pct) = if options.weighted {
+        let (avg, loss) = weighted_stats(results, options.source, options.penalty_microsecs);
+        (Some(avg.round() as i64), Some(loss))
+    } else {
+        (None, None)
+    };
+    let histogram = options.histogram.then(|| histogram(&successful_times));
+
+    let responsive = || {
+        results
+            .iter()
+            .filter_map(|r| r.best_time_microsecs.map(|best| (r, best)))
+    };
+    let report = |extreme: Option<(&HostResult, f64)>| {
+        extreme.map(|(r, _)| r.report(location.as_ref(), options.max_rtt_microsecs))
+    };
+    let (countries, cities) = geo_coverage(results, options.geo_coverage);
+    let worst_host = report(responsive().max_by(|(_, a), (_, b)| a.total_cmp(b)));
+    let best_host = report(responsive().min_by(|(_, a), (_, b)| a.total_cmp(b)));
+
     if successful_times.is_empty() {
         return Statistics {
+            schema_version: SCHEMA_VERSION.to_string(),
             timestamp,
+            source_hostname: None,
+            rollping_version: None,
             avg_microsecs: 0,
-            median_microsecs: 0,
+            avg_samples: 0,
+            avg_ewma_microsecs: None,
+            avg_ci_low_microsecs: options.confidence_interval.then_some(0),
+            avg_ci_high_microsecs: options.confidence_interval.then_some(0),
+            geomean_microsecs: 0,
+            median_best_microsecs: 0,
+            median_all_microsecs: (options.source == StatsSource::All).then_some(0),
             p95_microsecs: 0,
             p99_microsecs: 0,
+            min_microsecs: 0,
             max_microsecs: 0,
+            stddev_microsecs: 0,
+            mad_microsecs: options.robust.then_some(0),
+            jitter_microsecs: 0,
+            packet_loss_pct,
+            weighted_avg_microsecs,
+            weighted_packet_loss_pct,
             non_responsive_nodes,
+            unresolved_hosts,
+            deadline_exceeded_hosts,
+            skipped_hosts,
+            slow_hosts,
+            // Nothing replied, so no target was met
+            health_score: options.sla.map(|_| 0.0),
+            ipv4_hosts,
+            ipv6_hosts,
             total_hosts,
-            pings_per_host,
-            timeout_secs,
+            pings_per_host: options.pings_per_host,
+            stats_source: options.source,
+            timeout_secs: options.timeout_secs,
+            total_duration_secs: 0.0,
+            interrupted: false,
+            histogram,
             location,
+            countries,
+            cities,
+            worst_host,
+            best_host,
+            grouped: None,
+            comparison: None,
+            hosts: None,
         };
     }
 
     successful_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
-    let avg_microsecs =
-        (successful_times.iter().sum::<f64>() / successful_times.len() as f64).round() as i64;
+    let mean = successful_times.iter().sum::<f64>() / successful_times.len() as f64;
+    let variance = successful_times
+        .iter()
+        .map(|t| (t - mean).powi(2))
+        .sum::<f64>()
+        / successful_times.len() as f64;
+
+    let avg_microsecs = mean.round() as i64;
+    let geomean_microsecs = geometric_mean(&successful_times).round() as i64;
     let median_microsecs = percentile(&successful_times, 50.0).round() as i64;
+    let (median_best_microsecs, median_all_microsecs) = match best_times {
+        None => (median_microsecs, None),
+        Some(mut best_times) => {
+            best_times.sort_by(f64::total_cmp);
+            let median_best = percentile(&best_times, 50.0).round() as i64;
+            (median_best, Some(median_microsecs))
+        }
+    };
     let p95_microsecs = percentile(&successful_times, 95.0).round() as i64;
     let p99_microsecs = percentile(&successful_times, 99.0).round() as i64;
+    let min_microsecs = successful_times.first().unwrap().round() as i64;
     let max_microsecs = successful_times.last().unwrap().round() as i64;
+    let stddev_microsecs = variance.sqrt().round() as i64;
+    let jitter_microsecs = mean_jitter(results).round() as i64;
+    let mad_microsecs = options
+        .robust
+        .then(|| median_absolute_deviation(&successful_times).round() as i64);
+    let health_score = options
+        .sla
+        .map(|sla| health_score(p95_microsecs as f64, packet_loss_pct, sla));
+    let (avg_ci_low_microsecs, avg_ci_high_microsecs) = if options.confidence_interval {
+        let (low, high) = confidence_interval(&successful_times, mean);
+        (Some(low.round() as i64), Some(high.round() as i64))
+    } else {
+        (None, None)
+    };
 
     Statistics {
+        schema_version: SCHEMA_VERSION.to_string(),
         timestamp,
+        source_hostname: None,
+        rollping_version: None,
         avg_microsecs,
-        median_microsecs,
+        avg_samples: successful_times.len(),
+        avg_ewma_microsecs: None,
+        avg_ci_low_microsecs,
+        avg_ci_high_microsecs,
+        geomean_microsecs,
+        median_best_microsecs,
+        median_all_microsecs,
         p95_microsecs,
         p99_microsecs,
+        min_microsecs,
         max_microsecs,
+        stddev_microsecs,
+        mad_microsecs,
+        jitter_microsecs,
+        packet_loss_pct,
+        weighted_avg_microsecs,
+        weighted_packet_loss_pct,
         non_responsive_nodes,
+        unresolved_hosts,
+        deadline_exceeded_hosts,
+        skipped_hosts,
+        slow_hosts,
+        health_score,
+        ipv4_hosts,
+        ipv6_hosts,
         total_hosts,
-        pings_per_host,
-        timeout_secs,
+        pings_per_host: options.pings_per_host,
+        stats_source: options.source,
+        timeout_secs: options.timeout_secs,
+        total_duration_secs: 0.0,
+        interrupted: false,
+        histogram,
         location,
+        countries,
+        cities,
+        worst_host,
+        best_host,
+        grouped: None,
+        comparison: None,
+        hosts: None,
+    }
+}
+
+/// 95% confidence interval of the mean, as mean ± 1.96 standard errors. A
+/// single sample gives an interval of just the mean.
+fn confidence_interval(times: &[f64], mean: f64) -> (f64, f64) {
+    let n = times.len() as f64;
+    if times.len() < 2 {
+        return (mean, mean);
+    }
+    let sample_variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    let margin = 1.96 * (sample_variance / n).sqrt();
+    (mean - margin, mean + margin)
+}
+
+/// Median of the absolute deviations from the median of `sorted`, which must
+/// be sorted ascending
+fn median_absolute_deviation(sorted: &[f64]) -> f64 {
+    let median = percentile(sorted, 50.0);
+    let mut deviations: Vec<f64> = sorted.iter().map(|t| (t - median).abs()).collect();
+    deviations.sort_by(f64::total_cmp);
+    percentile(&deviations, 50.0)
+}
+
+/// Count ping times into the fixed HISTOGRAM_BOUNDS_MS buckets plus an overflow bucket
+fn histogram(times_microsecs: &[f64]) -> Vec<Bucket> {
+    let mut buckets: Vec<Bucket> = HISTOGRAM_BOUNDS_MS
+        .iter()
+        .map(|&ms| Bucket {
+            le_microsecs: Some(ms * 1000),
+            count: 0,
+        })
+        .chain([Bucket {
+            le_microsecs: None,
+            count: 0,
+        }])
+        .collect();
+    for &time in times_microsecs {
+        let index = HISTOGRAM_BOUNDS_MS
+            .iter()
+            .position(|&ms| time <= (ms * 1000) as f64)
+            .unwrap_or(HISTOGRAM_BOUNDS_MS.len());
+        buckets[index].count += 1;
+    }
+    buckets
+}
+
+/// This machine's host name, for --metadata
+fn local_hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname writes at most `buf.len()` bytes into `buf`
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        warn!(
+            "Failed to read the host name: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    // The name is NUL-terminated unless it filled the whole buffer
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// Responsive hosts per country or city (see `geo_coverage`)
+type Coverage = Option<BTreeMap<String, usize>>;
+
+/// Count the responsive hosts in each country, and at the city level in each
+/// city; hosts without a location are not counted
+fn geo_coverage(results: &[HostResult], level: Option<GeoLevel>) -> (Coverage, Coverage) {
+    let Some(level) = level else {
+        return (None, None);
+    };
+    let mut countries = BTreeMap::new();
+    let mut cities = BTreeMap::new();
+    for location in results
+        .iter()
+        .filter(|r| r.status == HostStatus::Responsive)
+        .filter_map(|r| r.location.as_ref())
+    {
+        if let Some(code) = &location.country_code {
+            *countries.entry(code.clone()).or_insert(0) += 1;
+            if let Some(city) = &location.city {
+                *cities.entry(format!("{}, {}", city, code)).or_insert(0) += 1;
+            }
+        }
+    }
+    (Some(countries), (level == GeoLevel::City).then_some(cities))
+}
+
+/// Format `time` as an RFC 3339 UTC timestamp with microseconds, e.g.
+/// `2025-11-17T23:20:27.123456Z`
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// Seconds since the Unix epoch
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Seconds since `start`, rounded to milliseconds
+fn elapsed_secs(start: Instant) -> f64 {
+    (start.elapsed().as_secs_f64() * 1000.0).round() / 1000.0
+}
+
+/// The --score health score, rounded to one decimal. p95 latency and packet
+/// loss each score 100 at or under their target, falling linearly to 0 at twice
+/// the target (at once, for a target of 0), and the run gets the lower of the two.
+fn health_score(p95_microsecs: f64, loss_pct: f64, sla: Sla) -> f64 {
+    let component = |value: f64, target: f64| {
+        if value <= target {
+            1.0
+        } else if target == 0.0 {
+            0.0
+        } else {
+            (2.0 - value / target).max(0.0)
+        }
+    };
+    let score =
+        100.0 * component(p95_microsecs, sla.p95_microsecs).min(component(loss_pct, sla.loss_pct));
+    (score * 10.0).round() / 10.0
+}
+
+/// Percentage of attempted pings that got no reply, rounded to two decimals
+/// A duration in whole microseconds, rounded like the other reported latencies
+fn microsecs(duration: Duration) -> i64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() as i64
+}
+
+fn loss_pct(attempted: usize, successful: usize) -> f64 {
+    if attempted == 0 {
+        return 0.0;
+    }
+    let loss = (attempted - successful) as f64 / attempted as f64;
+    (loss * 10_000.0).round() / 100.0
+}
+
+/// Average, across hosts with at least two successful pings, of the mean absolute
+/// difference between consecutive ping times.
+fn mean_jitter(results: &[HostResult]) -> f64 {
+    let per_host: Vec<f64> = results
+        .iter()
+        .filter(|r| r.samples_microsecs.len() >= 2)
+        .map(|r| {
+            let diffs = r.samples_microsecs.windows(2).map(|w| (w[1] - w[0]).abs());
+            diffs.sum::<f64>() / (r.samples_microsecs.len() - 1) as f64
+        })
+        .collect();
+
+    if per_host.is_empty() {
+        return 0.0;
+    }
+    per_host.iter().sum::<f64>() / per_host.len() as f64
+}
+
+/// Exponential of the mean of the natural logs. Non-positive values are skipped
+/// since their log is undefined.
+fn geometric_mean(values: &[f64]) -> f64 {
+    let logs: Vec<f64> = values
+        .iter()
+        .filter(|&&v| v > 0.0)
+        .map(|v| v.ln())
+        .collect();
+    if logs.is_empty() {
+        return 0.0;
     }
+    (logs.iter().sum::<f64>() / logs.len() as f64).exp()
 }
 
+/// Percentile of sorted values, linearly interpolating between the two nearest
+/// ranks (the "R-7" method used by most spreadsheets and NumPy)
 fn percentile(sorted_values: &[f64], p: f64) -> f64 {
     if sorted_values.is_empty() {
         return 0.0;
     }
 
-    let idx = (p / 100.0 * (sorted_values.len() - 1) as f64).round() as usize;
-    sorted_values[idx.min(sorted_values.len() - 1)]
+    let rank =
+        (p / 100.0 * (sorted_values.len() - 1) as f64).clamp(0.0, (sorted_values.len() - 1) as f64);
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let fraction = rank - lower as f64;
+    sorted_values[lower] + (sorted_values[upper] - sorted_values[lower]) * fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::input::tests::entry;
+
+    fn ping_options(count: usize) -> PingOptions {
+        PingOptions {
+            count,
+            warmup: 0,
+            retries: 0,
+            best_n: 1,
+            timeout: Duration::from_secs(2),
+            interval: Duration::ZERO,
+            payload_size: 8,
+            random_payload: false,
+            trace: false,
+            randomness: Arc::new(Randomness::new(None)),
+            ttl: None,
+            traceroute: None,
+            rate_limiter: None,
+            dscp: None,
+            hw_timestamps: false,
+            source_address: None,
+            interface: None,
+            tcp_port: None,
+            socks5: None,
+            dry_run: false,
+            dedup_resolved: false,
+            shuffle: false,
+            all_addresses: false,
+            stop: None,
+            deadline: None,
+            family: None,
+            resolver: None,
+            reverse_resolver: None,
+            dns_cache: Arc::new(DnsCache::new(Duration::ZERO)),
+            geoip: None,
+            geofence: None,
+        }
+    }
+
+    fn stats_options() -> StatsOptions {
+        StatsOptions {
+            source: StatsSource::Best,
+            histogram: false,
+            confidence_interval: false,
+            robust: false,
+            weighted: false,
+            penalty_microsecs: None,
+            max_rtt_microsecs: None,
+            sla: None,
+            deadline: false,
+            geofence: false,
+            geo_coverage: None,
+            pings_per_host: 3,
+            timeout_secs: 2.0,
+        }
+    }
+
+    /// A host whose `samples` (in microseconds) answered out of `attempted` pings
+    fn host(samples: &[f64], attempted: usize) -> HostResult {
+        let mut result = HostResult::new(&entry("192.0.2.1"), &ping_options(attempted));
+        result.ip = Some("192.0.2.1".parse().unwrap());
+        result.successful_pings = samples.len();
+        result.samples_microsecs = samples.to_vec();
+        result.best_time_microsecs = best_time(samples, 1);
+        if !samples.is_empty() {
+            result.status = HostStatus::Responsive;
+        }
+        result
+    }
+
+    fn statistics(results: &[HostResult], options: &StatsOptions) -> serde_json::Value {
+        serde_json::to_value(calculate_statistics(results, options, 0, None)).unwrap()
+    }
+
+    #[test]
+    fn min_of_a_single_host() {
+        let stats = statistics(&[host(&[1500.0, 900.0, 1200.0], 3)], &stats_options());
+        assert_eq!(stats["min_microsecs"], 900);
+        assert_eq!(stats["max_microsecs"], 900);
+    }
+
+    #[test]
+    fn min_across_hosts() {
+        let results = [host(&[2100.0], 1), host(&[], 1), host(&[640.0], 1)];
+        let stats = statistics(&results, &stats_options());
+        assert_eq!(stats["min_microsecs"], 640);
+        assert_eq!(stats["max_microsecs"], 2100);
+    }
+
+    #[test]
+    fn min_without_samples() {
+        let stats = statistics(&[], &stats_options());
+        assert_eq!(stats["min_microsecs"], 0);
+        let stats = statistics(&[host(&[], 3)], &stats_options());
+        assert_eq!(stats["min_microsecs"], 0);
+        assert_eq!(stats["non_responsive_nodes"], 1);
+    }
+
+    #[test]
+    fn packet_loss_counts_partial_failures() {
+        let results = [host(&[800.0, 900.0], 5), host(&[700.0; 5], 5)];
+        let stats = statistics(&results, &stats_options());
+        // 3 of 10 pings lost, though both hosts answered
+        assert_eq!(stats["packet_loss_pct"], 30.0);
+        assert_eq!(stats["non_responsive_nodes"], 0);
+        assert_eq!(
+            host(&[800.0, 900.0], 5).report(None, None).packet_loss_pct,
+            60.0
+        );
+    }
+
+    #[test]
+    fn packet_loss_rounding() {
+        assert_eq!(loss_pct(3, 1), 66.67);
+        assert_eq!(loss_pct(0, 0), 0.0);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_bounds_hosts_in_flight() {
+        let mut options = ping_options(1);
+        options.tcp_port = Some(80);
+        let (proxy, most_in_flight) = socks_proxy(false, Duration::from_millis(50)).await;
+        options.socks5 = Some(proxy);
+        let hosts: Vec<String> = (1..=12).map(|i| format!("127.0.0.{}", i)).collect();
+        let (tx, rx) = mpsc::channel(hosts.len());
+        for host in &hosts {
+            tx.send(entry(host)).await.unwrap();
+        }
+        drop(tx);
+
+        let results = ping_hosts(rx, &options, 3, None, None).await;
+        let pinged: Vec<&str> = results.iter().map(|r| r.host.as_str()).collect();
+        assert_eq!(pinged, hosts);
+        assert!(results.iter().all(|r| r.status == HostStatus::Responsive));
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    /// Ping `host` with `options` and return its one result
+    async fn ping(host: &str, options: &PingOptions) -> HostResult {
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(entry(host)).await.unwrap();
+        drop(tx);
+        let mut results = ping_hosts(rx, options, 1, None, None).await;
+        assert_eq!(results.len(), 1);
+        results.remove(0)
+    }
+
+    #[test]
+    fn payload_size_limits() {
+        let parse = |size: &str| Args::try_parse_from(["rollping", "--payload-size", size]);
+        assert_eq!(parse("1472").unwrap().payload_size, 1472);
+        assert_eq!(parse("65507").unwrap().payload_size, 65507);
+        assert!(parse("0").is_err());
+        assert!(parse("65508").is_err());
+    }
+
+    #[tokio::test]
+    async fn large_payload_round_trips() {
+        let mut options = ping_options(2);
+        options.payload_size = 1472;
+        let result = ping("127.0.0.1", &options).await;
+        assert_eq!(result.status, HostStatus::Responsive);
+        assert_eq!(result.successful_pings, 2);
+    }
+
+    #[test]
+    fn geometric_mean_of_a_small_sample() {
+        assert!((geometric_mean(&[1.0, 10.0, 100.0]) - 10.0).abs() < 1e-9);
+        // sqrt(2000 * 8000)
+        let stats = statistics(&[host(&[2000.0], 1), host(&[8000.0], 1)], &stats_options());
+        assert_eq!(stats["geomean_microsecs"], 4000);
+        assert_eq!(stats["avg_microsecs"], 5000);
+    }
+
+    #[test]
+    fn geometric_mean_skips_non_positive_values() {
+        assert!((geometric_mean(&[0.0, -1.0, 4.0, 9.0]) - 6.0).abs() < 1e-9);
+        assert_eq!(geometric_mean(&[0.0]), 0.0);
+        assert_eq!(geometric_mean(&[]), 0.0);
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        assert_eq!(percentile(&[1.0, 2.0, 3.0, 4.0], 50.0), 2.5);
+        let values: Vec<f64> = (1..=10).map(f64::from).collect();
+        assert!((percentile(&values, 95.0) - 9.55).abs() < 1e-9);
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 100.0), 10.0);
+    }
+
+    #[test]
+    fn percentile_edge_cases() {
+        assert_eq!(percentile(&[], 50.0), 0.0);
+        assert_eq!(percentile(&[7.0], 99.0), 7.0);
+        assert_eq!(percentile(&[1.0, 2.0], 150.0), 2.0);
+    }
+
+    /// A SOCKS5 proxy that refuses every other connect request, starting with
+    /// the first, and pretends to connect the rest
+    /// A SOCKS5 proxy that answers each connect after `delay`, refusing every
+    /// other one if `flaky`. Also returns the most connects it had open at once.
+    async fn socks_proxy(flaky: bool, delay: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let most = most_in_flight.clone();
+        tokio::spawn(async move {
+            for n in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (in_flight, most) = (in_flight.clone(), most.clone());
+                tokio::spawn(async move {
+                    let open = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(open, Ordering::SeqCst);
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    stream.write_all(&[5, 0]).await.unwrap();
+                    // An IPv4 connect request
+                    let mut request = [0u8; 10];
+                    stream.read_exact(&mut request).await.unwrap();
+                    sleep(delay).await;
+                    let reply = if flaky && n % 2 == 0 { 5 } else { 0 };
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    stream
+                        .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+        (addr, most_in_flight)
+    }
+
+    #[tokio::test]
+    async fn retries_rescue_lost_pings() {
+        let mut options = ping_options(3);
+        options.tcp_port = Some(80);
+        options.socks5 = Some(socks_proxy(true, Duration::ZERO).await.0);
+        options.retries = 1;
+        let result = ping("127.0.0.1", &options).await;
+        assert_eq!(result.status, HostStatus::Responsive);
+        assert_eq!(result.successful_pings, 3);
+        // Retries don't count as attempts
+        assert_eq!(result.attempted_pings, 3);
+    }
+
+    #[tokio::test]
+    async fn without_retries_failures_are_lost() {
+        let mut options = ping_options(4);
+        options.tcp_port = Some(80);
+        options.socks5 = Some(socks_proxy(true, Duration::ZERO).await.0);
+        let result = ping("127.0.0.1", &options).await;
+        assert_eq!(result.successful_pings, 2);
+        assert_eq!(result.attempted_pings, 4);
+        assert_eq!(
+            result.error_kinds.get(&PingErrorKind::ProxyFailed),
+            Some(&2)
+        );
+    }
+
+    #[test]
+    fn confidence_interval_of_a_known_sample() {
+        // Sample variance 16/3, so the margin is 1.96 * sqrt(16/3 / 4) = 2.2632
+        let times = [1000.0, 1000.0, 1004.0, 1004.0];
+        let (low, high) = confidence_interval(&times, 1002.0);
+        assert!((low - 999.7368).abs() < 1e-4);
+        assert!((high - 1004.2632).abs() < 1e-4);
+
+        let mut options = stats_options();
+        options.confidence_interval = true;
+        let results: Vec<HostResult> = times.iter().map(|&t| host(&[t], 1)).collect();
+        let stats = statistics(&results, &options);
+        assert_eq!(stats["avg_ci_low_microsecs"], 1000);
+        assert_eq!(stats["avg_ci_high_microsecs"], 1004);
+    }
+
+    #[test]
+    fn confidence_interval_of_one_sample() {
+        assert_eq!(confidence_interval(&[1234.0], 1234.0), (1234.0, 1234.0));
+        // Left out unless asked for
+        let stats = statistics(&[host(&[1234.0], 1)], &stats_options());
+        assert!(stats.get("avg_ci_low_microsecs").is_none());
+    }
+
+    #[test]
+    fn median_absolute_deviation_of_a_skewed_sample() {
+        // Median 2; deviations 1, 1, 0, 0, 2, 4, 7 have median 1
+        assert_eq!(
+            median_absolute_deviation(&[1.0, 1.0, 2.0, 2.0, 4.0, 6.0, 9.0]),
+            1.0
+        );
+        assert_eq!(median_absolute_deviation(&[5.0]), 0.0);
+    }
+
+    #[test]
+    fn mad_only_with_robust_stats() {
+        let results: Vec<HostResult> = [1000.0, 1000.0, 2000.0, 2000.0, 4000.0, 6000.0, 9000.0]
+            .iter()
+            .map(|&t| host(&[t], 1))
+            .collect();
+        let mut options = stats_options();
+        assert!(
+            statistics(&results, &options)
+                .get("mad_microsecs")
+                .is_none()
+        );
+        options.robust = true;
+        assert_eq!(statistics(&results, &options)["mad_microsecs"], 1000);
+        assert_eq!(statistics(&[], &options)["mad_microsecs"], 0);
+    }
+
+    #[tokio::test]
+    async fn many_pings_without_false_timeouts() {
+        // Hosts that share an address share its replies, so sequence numbers
+        // are all that tells them apart
+        let hosts = [
+            "127.0.0.1",
+            "127.0.0.1",
+            "127.0.0.2",
+            "127.0.0.3",
+            "127.0.0.1",
+        ];
+        let (tx, rx) = mpsc::channel(hosts.len());
+        for host in hosts {
+            tx.send(entry(host)).await.unwrap();
+        }
+        drop(tx);
+
+        let results = ping_hosts(rx, &ping_options(50), hosts.len(), None, None).await;
+        assert_eq!(results.len(), hosts.len());
+        for result in results {
+            assert_eq!(result.successful_pings, 50, "{:?}", result.error_kinds);
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_increase() {
+        let clients = PingClients::default();
+        let first = clients.next_sequence().0;
+        assert_eq!(clients.next_sequence().0, first + 1);
+        assert_eq!(clients.next_sequence().0, first + 2);
+    }
+
+    #[test]
+    fn weighted_statistics() {
+        let mut heavy = host(&[1000.0; 4], 4);
+        heavy.weight = Some(9.0);
+        // Every ping lost; counts once
+        let light = host(&[], 4);
+        let mut options = stats_options();
+        options.weighted = true;
+        let stats = statistics(&[heavy, light], &options);
+        assert_eq!(stats["packet_loss_pct"], 50.0);
+        assert_eq!(stats["weighted_packet_loss_pct"], 10.0);
+        assert_eq!(stats["weighted_avg_microsecs"], 1000);
+    }
 }
@@ -0,0 +1,700 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{Failure, HostReport, Location, Statistics, StatsSource};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Single-line JSON object
+    Json,
+    /// Indented JSON object (selected with --json-pretty)
+    #[value(skip)]
+    JsonPretty,
+    /// Header row followed by one data row (or one row per host with --per-host)
+    Csv,
+    /// Aligned key/value summary (plus a host table with --per-host)
+    Table,
+    /// Prometheus text exposition format, e.g. for the node_exporter textfile collector
+    Prometheus,
+    /// InfluxDB line protocol, one summary line (plus one line per host with --per-host)
+    Influx,
+}
+
+/// Where rendered output goes: stdout, or a file or socket opened once for the
+/// whole run
+#[derive(Clone, Default)]
+pub struct Sink {
+    target: Option<Arc<Mutex<Target>>>,
+}
+
+enum Target {
+    File(File),
+    /// `None` once the socket has failed and output has fallen back to stdout
+    Socket(Option<UnixStream>),
+}
+
+impl Sink {
+    /// Open `path` for writing, truncating it unless `append` is set
+    pub fn open(path: &Path, append: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .with_context(|| format!("Failed to open output file {:?}", path))?;
+        Ok(Sink::new(Target::File(file)))
+    }
+
+    /// Connect to the Unix socket at `path`, falling back to stdout if that fails
+    pub fn connect(path: &Path) -> Self {
+        match UnixStream::connect(path) {
+            Ok(stream) => Sink::new(Target::Socket(Some(stream))),
+            Err(e) => {
+                warn!(
+                    "Failed to connect to output socket {:?}: {}; writing to stdout instead",
+                    path, e
+                );
+                Sink::default()
+            }
+        }
+    }
+
+    fn new(target: Target) -> Self {
+        Sink {
+            target: Some(Arc::new(Mutex::new(target))),
+        }
+    }
+
+    /// Write `text` followed by a newline. The write blocks, so it runs on the
+    /// blocking thread pool rather than holding up the pings.
+    pub async fn write_line(&self, text: String) -> Result<()> {
+        let sink = self.clone();
+        tokio::task::spawn_blocking(move || sink.write_line_blocking(&text))
+            .await
+            .context("Output writer panicked")?
+    }
+
+    fn write_line_blocking(&self, text: &str) -> Result<()> {
+        let Some(target) = &self.target else {
+            return write_stdout(text);
+        };
+        match &mut *target.lock().unwrap() {
+            Target::File(file) => {
+                writeln!(file, "{}", text).context("Failed to write to output file")
+            }
+            Target::Socket(socket) => {
+                if let Some(stream) = socket {
+                    match writeln!(stream, "{}", text) {
+                        Ok(()) => return Ok(()),
+                        Err(e) => {
+                            warn!(
+                                "Failed to write to output socket: {}; writing to stdout instead",
+                                e
+                            );
+                            *socket = None;
+                        }
+                    }
+                }
+                write_stdout(text)
+            }
+        }
+    }
+}
+
+/// Write `text` and a newline to stdout, returning an error rather than
+/// panicking if it has been closed, such as by `| head`
+pub fn write_stdout(text: &str) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", text)
+        .and_then(|()| stdout.flush())
+        .context("Failed to write to stdout")
+}
+
+/// Whether `error` came from writing to a reader that has gone away
+pub fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Serialize the statistics in the requested format, without a trailing newline.
+/// Fractional values are rounded to `precision` decimals.
+pub fn render(stats: &Statistics, format: OutputFormat, precision: u8) -> Result<String> {
+    match format {
+        OutputFormat::Json => to_json(stats, precision),
+        OutputFormat::JsonPretty => Ok(serde_json::to_string_pretty(&to_value(stats, precision)?)?),
+        OutputFormat::Csv => match &stats.hosts {
+            Some(hosts) => to_csv(hosts, &columns(&HostReport::csv_template())?, precision),
+            None => to_csv(
+                std::slice::from_ref(stats),
+                &summary_columns(stats)?,
+                precision,
+            ),
+        },
+        OutputFormat::Table => to_table(stats, precision),
+        OutputFormat::Prometheus => Ok(to_prometheus(stats)),
+        OutputFormat::Influx => Ok(to_influx(stats)),
+    }
+}
+
+/// Numeric statistics fields --print-only can print. Each `*_microsecs` field
+/// can also be asked for as `*_ms`, in milliseconds.
+const PRINTABLE_FIELDS: &[&str] = &[
+    "timestamp",
+    "avg_microsecs",
+    "avg_ewma_microsecs",
+    "avg_ci_low_microsecs",
+    "avg_ci_high_microsecs",
+    "geomean_microsecs",
+    "median_best_microsecs",
+    "median_all_microsecs",
+    "p95_microsecs",
+    "p99_microsecs",
+    "min_microsecs",
+    "max_microsecs",
+    "stddev_microsecs",
+    "mad_microsecs",
+    "jitter_microsecs",
+    "packet_loss_pct",
+    "weighted_avg_microsecs",
+    "weighted_packet_loss_pct",
+    "non_responsive_nodes",
+    "unresolved_hosts",
+    "deadline_exceeded_hosts",
+    "skipped_hosts",
+    "slow_hosts",
+    "health_score",
+    "ipv4_hosts",
+    "ipv6_hosts",
+    "total_hosts",
+    "pings_per_host",
+    "timeout_secs",
+    "total_duration_secs",
+];
+
+/// Check a --print-only field name, returning the JSON field it reads and
+/// whether to convert it from microseconds to milliseconds
+pub fn printable_field(name: &str) -> Result<(&'static str, bool), String> {
+    let millis = name
+        .strip_suffix("_ms")
+        .map(|base| format!("{}_microsecs", base));
+    let (field, millis) = match &millis {
+        Some(field) => (field.as_str(), true),
+        None => (name, false),
+    };
+    PRINTABLE_FIELDS
+        .iter()
+        .find(|&&known| known == field)
+        .map(|&known| (known, millis))
+        .ok_or_else(|| {
+            format!(
+                "unknown field (expected one of: {}, or a *_microsecs field as *_ms)",
+                PRINTABLE_FIELDS.join(", ")
+            )
+        })
+}
+
+/// One field of the statistics as a bare number, for --print-only
+pub fn render_field(stats: &Statistics, name: &str, precision: u8) -> Result<String> {
+    let (field, millis) = printable_field(name).map_err(anyhow::Error::msg)?;
+    let value = serde_json::to_value(stats)?;
+    let Some(mut number) = value.get(field).and_then(Value::as_f64) else {
+        anyhow::bail!(Failure::usage(format!(
+            "--print-only {}: {} is not in the output of this run; it needs the flag that adds it",
+            name, field
+        )));
+    };
+    if millis {
+        number /= 1000.0;
+    }
+    let scale = 10f64.powi(precision as i32);
+    number = (number * scale).round() / scale;
+    Ok(number.to_string())
+}
+
+/// Serialize a record as one line of JSON
+pub fn to_json<T: Serialize>(record: &T, precision: u8) -> Result<String> {
+    Ok(serde_json::to_string(&to_value(record, precision)?)?)
+}
+
+fn to_value<T: Serialize>(record: &T, precision: u8) -> Result<Value> {
+    let mut value = serde_json::to_value(record)?;
+    round_floats(&mut value, precision);
+    Ok(value)
+}
+
+/// Round every fractional number in `value` to `decimals` places, leaving
+/// integers alone so they keep serializing without a decimal point
+fn round_floats(value: &mut Value, decimals: u8) {
+    match value {
+        Value::Number(n) if n.is_f64() => {
+            let scale = 10f64.powi(decimals as i32);
+            let rounded = n.as_f64().map(|f| (f * scale).round() / scale);
+            if let Some(rounded) = rounded.and_then(serde_json::Number::from_f64) {
+                *n = rounded;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| round_floats(v, decimals)),
+        Value::Object(map) => map.values_mut().for_each(|v| round_floats(v, decimals)),
+        _ => {}
+    }
+}
+
+/// Flatten a record into `(column, value)` pairs. Nested objects become
+/// `parent_child` columns and arrays are skipped.
+fn flatten<T: Serialize>(record: &T, precision: u8) -> Result<Vec<(String, Value)>> {
+    let mut fields = Vec::new();
+    if let Value::Object(map) = to_value(record, precision)? {
+        for (key, value) in map {
+            match value {
+                Value::Object(nested) => {
+                    // Only one level is flattened; deeper structure such as
+                    // tag groups has no column
+                    for (sub_key, sub_value) in nested {
+                        if !sub_value.is_object() && !sub_value.is_array() {
+                            fields.push((format!("{}_{}", key, sub_key), sub_value));
+                        }
+                    }
+                }
+                Value::Array(_) => {}
+                value => fields.push((key, value)),
+            }
+        }
+    }
+    Ok(fields)
+}
+
+/// The flattened column names of `record`
+fn columns<T: Serialize>(record: &T) -> Result<Vec<String>> {
+    Ok(flatten(record, 0)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect())
+}
+
+/// The summary row's columns: those of `stats`, but with the host and location
+/// columns there whether or not this run filled them in, so every run with the
+/// same flags prints the same header
+fn summary_columns(stats: &Statistics) -> Result<Vec<String>> {
+    let mut template: Statistics = serde_json::from_value(serde_json::to_value(stats)?)?;
+    template.location = Some(Location::default());
+    template.worst_host = Some(HostReport::csv_template());
+    template.best_host = Some(HostReport::csv_template());
+    // Keyed by whatever the run found, so they have no fixed columns
+    template.countries = None;
+    template.cities = None;
+    columns(&template)
+}
+
+/// Render each record as a CSV row under a header of `columns`, leaving cells
+/// empty where a record has no value and dropping values with no column
+fn to_csv<T: Serialize>(records: &[T], columns: &[String], precision: u8) -> Result<String> {
+    let mut rows: Vec<Vec<(String, String)>> = Vec::new();
+    for record in records {
+        rows.push(
+            flatten(record, precision)?
+                .into_iter()
+                .map(|(key, value)| (key, csv_cell(&value)))
+                .collect(),
+        );
+    }
+
+    let mut lines = vec![columns.join(",")];
+    for row in rows {
+        let cells: Vec<&str> = columns
+            .iter()
+            .map(|column| {
+                row.iter()
+                    .find(|(key, _)| key == column)
+                    .map_or("", |(_, cell)| cell.as_str())
+            })
+            .collect();
+        lines.push(cells.join(","));
+    }
+    Ok(lines.join("\n"))
+}
+
+fn csv_cell(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Render the summary as aligned key/value pairs, followed by a host table
+/// when per-host results are present. Latencies are shown in milliseconds.
+fn to_table(stats: &Statistics, precision: u8) -> Result<String> {
+    let pairs: Vec<(String, String)> = flatten(stats, precision)?
+        .into_iter()
+        .map(|(key, value)| match key.strip_suffix("_microsecs") {
+            Some(label) => (label.to_string(), format_millis(value.as_f64())),
+            None => (key, text_cell(&value)),
+        })
+        .collect();
+
+    let key_width = pairs.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    let value_width = pairs.iter().map(|(_, v)| v.len()).max().unwrap_or(0);
+    let mut lines: Vec<String> = pairs
+        .iter()
+        .map(|(k, v)| format!("{:<key_width$}  {:>value_width$}", k, v))
+        .collect();
+
+    if let Some(hosts) = &stats.hosts {
+        let header = ["HOST", "IP", "BEST", "LOSS"];
+        let rows: Vec<[String; 4]> = hosts
+            .iter()
+            .map(|h| {
+                [
+                    h.host.clone(),
+                    h.ip.map_or("-".to_string(), |ip| ip.to_string()),
+                    format_millis(h.best_time_microsecs.map(|t| t as f64)),
+                    format!("{:.2} %", h.packet_loss_pct),
+                ]
+            })
+            .collect();
+
+        let mut widths = header.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let format_row = |cells: [&str; 4]| {
+            format!(
+                "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}",
+                cells[0],
+                cells[1],
+                cells[2],
+                cells[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            )
+        };
+
+        lines.push(String::new());
+        lines.push(format_row(header));
+        for row in &rows {
+            lines.push(format_row(row.each_ref().map(String::as_str)));
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+fn format_millis(microsecs: Option<f64>) -> String {
+    microsecs.map_or("-".to_string(), |us| format!("{:.2} ms", us / 1000.0))
+}
+
+fn text_cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn millis(microsecs: i64) -> f64 {
+    microsecs as f64 / 1000.0
+}
+
+fn ratio(pct: f64) -> f64 {
+    (pct * 100.0).round() / 10_000.0
+}
+
+/// The aggregate statistics as `(name, help, value)` metrics, with latencies in
+/// milliseconds and loss as a ratio
+fn summary_metrics(stats: &Statistics) -> Vec<(&'static str, String, f64)> {
+    // The latencies come from each host's best ping or from every ping, per
+    // --stats-source
+    let times = match stats.stats_source {
+        StatsSource::Best => "best ping time",
+        StatsSource::All => "ping time",
+    };
+    let mut summary = vec![
+        (
+            "avg_ms",
+            format!("Average {}", times),
+            millis(stats.avg_microsecs),
+        ),
+        (
+            "geomean_ms",
+            format!("Geometric mean {}", times),
+            millis(stats.geomean_microsecs),
+        ),
+        (
+            "median_best_ms",
+            "Median best ping time".to_string(),
+            millis(stats.median_best_microsecs),
+        ),
+        (
+            "p95_ms",
+            format!("95th percentile {}", times),
+            millis(stats.p95_microsecs),
+        ),
+        (
+            "p99_ms",
+            format!("99th percentile {}", times),
+            millis(stats.p99_microsecs),
+        ),
+        (
+            "min_ms",
+            format!("Minimum {}", times),
+            millis(stats.min_microsecs),
+        ),
+        (
+            "max_ms",
+            format!("Maximum {}", times),
+            millis(stats.max_microsecs),
+        ),
+        (
+            "stddev_ms",
+            format!("Standard deviation of {}s", times),
+            millis(stats.stddev_microsecs),
+        ),
+        (
+            "jitter_ms",
+            "Mean difference between consecutive pings".to_string(),
+            millis(stats.jitter_microsecs),
+        ),
+        (
+            "packet_loss_ratio",
+            "Fraction of all pings that got no reply".to_string(),
+            ratio(stats.packet_loss_pct),
+        ),
+        (
+            "non_responsive_hosts",
+            "Hosts that failed to respond".to_string(),
+            stats.non_responsive_nodes as f64,
+        ),
+        (
+            "unresolved_hosts",
+            "Hosts whose name could not be resolved".to_string(),
+            stats.unresolved_hosts as f64,
+        ),
+        (
+            "ipv4_hosts",
+            "Hosts that resolved to an IPv4 address".to_string(),
+            stats.ipv4_hosts as f64,
+        ),
+        (
+            "ipv6_hosts",
+            "Hosts that resolved to an IPv6 address".to_string(),
+            stats.ipv6_hosts as f64,
+        ),
+        (
+            "hosts",
+            "Hosts tested".to_string(),
+            stats.total_hosts as f64,
+        ),
+        (
+            "pings_per_host",
+            "Pings sent to each host".to_string(),
+            stats.pings_per_host as f64,
+        ),
+        (
+            "duration_seconds",
+            "Wall-clock duration of the run".to_string(),
+            stats.total_duration_secs,
+        ),
+    ];
+    if let Some(slow_hosts) = stats.slow_hosts {
+        summary.push((
+            "slow_hosts",
+            "Hosts slower than --max-rtt-ms".to_string(),
+            slow_hosts as f64,
+        ));
+    }
+    if let Some(health_score) = stats.health_score {
+        summary.push((
+            "health_score",
+            "Health score from 0 to 100 against the --score targets".to_string(),
+            health_score,
+        ));
+    }
+    summary
+}
+
+/// Render the statistics as Prometheus gauges, with one labeled series per
+/// host when per-host results are present. Latencies are in milliseconds.
+fn to_prometheus(stats: &Statistics) -> String {
+    let mut lines = Vec::new();
+    for (name, help, value) in summary_metrics(stats) {
+        push_metric_header(&mut lines, name, &help);
+        lines.push(format!("rollping_{} {}", name, value));
+    }
+    push_metric_header(
+        &mut lines,
+        "timestamp_seconds",
+        "Unix time the measurement was taken",
+    );
+    lines.push(format!("rollping_timestamp_seconds {}", stats.timestamp));
+
+    if let Some(hosts) = &stats.hosts {
+        let labels: Vec<String> = hosts
+            .iter()
+            .map(|h| {
+                let ip = h.ip.map(|ip| ip.to_string()).unwrap_or_default();
+                format!(
+                    "host=\"{}\",ip=\"{}\"",
+                    escape_label(&h.host),
+                    escape_label(&ip)
+                )
+            })
+            .collect();
+
+        push_metric_header(
+            &mut lines,
+            "host_up",
+            "Whether the host answered at least one ping",
+        );
+        for (host, labels) in hosts.iter().zip(&labels) {
+            let up = host.best_time_microsecs.is_some() as u8;
+            lines.push(format!("rollping_host_up{{{}}} {}", labels, up));
+        }
+        push_metric_header(&mut lines, "host_best_ms", "Best ping time to the host");
+        for (host, labels) in hosts.iter().zip(&labels) {
+            // Hosts that never replied have no best time, so no sample
+            if let Some(best) = host.best_time_microsecs {
+                lines.push(format!(
+                    "rollping_host_best_ms{{{}}} {}",
+                    labels,
+                    millis(best)
+                ));
+            }
+        }
+        push_metric_header(
+            &mut lines,
+            "host_packet_loss_ratio",
+            "Fraction of pings to the host that got no reply",
+        );
+        for (host, labels) in hosts.iter().zip(&labels) {
+            lines.push(format!(
+                "rollping_host_packet_loss_ratio{{{}}} {}",
+                labels,
+                ratio(host.packet_loss_pct)
+            ));
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Render the statistics as InfluxDB line protocol: a `rollping_summary` line,
+/// then a `rollping` line tagged with each host when per-host results are
+/// present. Every line carries the run's timestamp in nanoseconds.
+fn to_influx(stats: &Statistics) -> String {
+    let timestamp_ns = stats.timestamp as u128 * 1_000_000_000;
+    let fields: Vec<String> = summary_metrics(stats)
+        .into_iter()
+        .map(|(name, _, value)| format!("{}={}", name, value))
+        .collect();
+    let mut lines = vec![format!(
+        "rollping_summary {} {}",
+        fields.join(","),
+        timestamp_ns
+    )];
+
+    for host in stats.hosts.iter().flatten() {
+        let mut tags = vec![format!("host={}", escape_tag(&host.host))];
+        // Influx rejects empty tag values, so unknown ones are left out
+        if let Some(ip) = host.ip {
+            tags.push(format!("ip={}", escape_tag(&ip.to_string())));
+        }
+        if let Some(label) = host.label.as_deref().filter(|l| !l.is_empty()) {
+            tags.push(format!("label={}", escape_tag(label)));
+        }
+        let status = serde_json::to_value(host.status).unwrap_or_default();
+        if let Some(status) = status.as_str() {
+            tags.push(format!("status={}", status));
+        }
+
+        let mut fields = vec![format!("up={}", host.best_time_microsecs.is_some())];
+        if let Some(best) = host.best_time_microsecs {
+            fields.push(format!("best_ms={}", millis(best)));
+        }
+        fields.push(format!("successful_pings={}i", host.successful_pings));
+        fields.push(format!("packet_loss_ratio={}", ratio(host.packet_loss_pct)));
+        lines.push(format!(
+            "rollping,{} {} {}",
+            tags.join(","),
+            fields.join(","),
+            timestamp_ns
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Escape a tag value as the line protocol requires. Backslashes go first, so
+/// the ones added for the other characters are not escaped again.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn push_metric_header(lines: &mut Vec<String>, name: &str, help: &str) {
+    lines.push(format!("# HELP rollping_{} {}", name, help));
+    lines.push(format!("# TYPE rollping_{} gauge", name));
+}
+
+/// Escape a label value as the exposition format requires
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn floats_round_to_the_precision() {
+        assert_eq!(
+            to_json(&json!({ "ms": 12.3456 }), 2).unwrap(),
+            r#"{"ms":12.35}"#
+        );
+        assert_eq!(
+            to_json(&json!({ "ms": 12.3456 }), 0).unwrap(),
+            r#"{"ms":12.0}"#
+        );
+    }
+
+    #[test]
+    fn rounding_reaches_nested_values_and_spares_integers() {
+        let record =
+            json!({ "count": 7, "hosts": [{ "loss": 33.33333 }], "location": { "km": 1.23449 } });
+        assert_eq!(
+            to_json(&record, 3).unwrap(),
+            r#"{"count":7,"hosts":[{"loss":33.333}],"location":{"km":1.234}}"#
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    num::NonZeroU32,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep_until;
+
+/// Token bucket holding a single token, refilled `pps` times a second and shared
+/// by every ping task, so sends are spread evenly instead of going out in bursts
+pub struct RateLimiter {
+    period: Duration,
+    /// When the next token becomes available
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(pps: NonZeroU32) -> Self {
+        RateLimiter {
+            period: Duration::from_secs(1) / pps.get(),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for a token. Callers are served in the order they ask.
+    pub async fn acquire(&self) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let at = (*next).max(Instant::now());
+            *next = at + self.period;
+            at
+        };
+        sleep_until(at.into()).await;
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Mutex;
+
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+/// Where ping identifiers, random payloads, and the --shuffle order come from: an RNG seeded with
+/// --seed, so a run can be reproduced, or else the thread RNG
+pub struct Randomness {
+    seeded: Option<Mutex<StdRng>>,
+}
+
+impl Randomness {
+    pub fn new(seed: Option<u64>) -> Self {
+        Randomness {
+            seeded: seed.map(|seed| Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// A ping (or traceroute) identifier
+    pub fn ident(&self) -> u16 {
+        match &self.seeded {
+            Some(rng) => rng.lock().unwrap().random(),
+            None => rand::random(),
+        }
+    }
+
+    /// Fill a payload with random bytes
+    pub fn fill(&self, bytes: &mut [u8]) {
+        match &self.seeded {
+            Some(rng) => rng.lock().unwrap().fill(bytes),
+            None => rand::fill(bytes),
+        }
+    }
+
+    /// Put `items` in random order
+    pub fn shuffle<T>(&self, items: &mut [T]) {
+        match &self.seeded {
+            Some(rng) => items.shuffle(&mut *rng.lock().unwrap()),
+            None => items.shuffle(&mut rand::rng()),
+        }
+    }
+}
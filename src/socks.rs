@@ -0,0 +1,82 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const COMMAND_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Ask the SOCKS5 proxy at the other end of `stream` to connect to `target`,
+/// returning once the proxy reports the connection is up. Only the
+/// no-authentication method is offered.
+pub async fn connect(stream: &mut TcpStream, target: SocketAddr) -> Result<()> {
+    stream
+        .write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH])
+        .await?;
+    let mut choice = [0u8; 2];
+    stream
+        .read_exact(&mut choice)
+        .await
+        .context("proxy closed the connection")?;
+    if choice[0] != SOCKS_VERSION {
+        anyhow::bail!("not a SOCKS5 proxy");
+    }
+    if choice[1] != METHOD_NO_AUTH {
+        anyhow::bail!("proxy requires authentication, which is not supported");
+    }
+
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .context("proxy closed the connection")?;
+    if reply[1] != 0 {
+        anyhow::bail!("proxy could not connect: {}", reply_message(reply[1]));
+    }
+    // Read past the address the proxy bound, which is not needed
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => anyhow::bail!("proxy replied with unknown address type {}", atyp),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// What a SOCKS5 reply code means (RFC 1928 section 6)
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::AsRawFd,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::debug;
+
+use crate::{PingFailure, trace};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Ask for software timestamps, and hardware ones where the NIC already has
+/// timestamping switched on, of both sent and received packets. Sent packets'
+/// timestamps come back on the error queue without a copy of the packet.
+const TIMESTAMPING_FLAGS: libc::c_uint = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_SOFTWARE
+    | libc::SOF_TIMESTAMPING_TX_HARDWARE
+    | libc::SOF_TIMESTAMPING_RX_HARDWARE
+    | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+    | libc::SOF_TIMESTAMPING_OPT_TSONLY;
+
+/// Which clock a timestamped ping's RTT was measured with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// The NIC's own clock, as the packets crossed the wire
+    Hardware,
+    /// The kernel's clock, as the packets passed the network stack
+    Kernel,
+    /// The kernel reported no usable timestamps, so the RTT was timed here
+    Userspace,
+}
+
+/// How the socket for each timestamped ping is set up
+pub struct SocketOptions<'a> {
+    pub ttl: Option<u32>,
+    pub dscp: Option<u8>,
+    pub source: Option<IpAddr>,
+    pub interface: Option<&'a str>,
+}
+
+/// A packet's time in software and hardware, where the kernel reported one
+#[derive(Default)]
+struct Stamps {
+    software: Option<Duration>,
+    hardware: Option<Duration>,
+}
+
+/// Send one ICMP echo request on a socket of its own with SO_TIMESTAMPING on,
+/// and time the reply from the kernel's (or NIC's) send and receive timestamps
+/// instead of from when this thread got to see them. Uses an unprivileged ping
+/// socket where allowed, else a raw one. Blocks the calling thread.
+pub fn ping(
+    target: IpAddr,
+    ident: u16,
+    seq: u16,
+    payload: &[u8],
+    timeout: Duration,
+    options: &SocketOptions,
+) -> Result<(Duration, Clock)> {
+    let (socket, raw) = open(target, options)?;
+    let request = trace::echo_request(target, ident, seq, payload);
+    let deadline = Instant::now() + timeout;
+    let sent_at = Instant::now();
+    socket.send_to(&request, &SocketAddr::new(target, 0).into())?;
+
+    let mut sent = None;
+    let mut buf = [0u8; 1500];
+    let (received, received_at) = loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(PingFailure::timeout().into());
+        }
+        let events = wait(&socket, deadline - now)?;
+        if events & libc::POLLERR != 0
+            && let Some((_, stamps)) = receive(&socket, &mut buf, libc::MSG_ERRQUEUE)?
+        {
+            sent = Some(stamps);
+        }
+        if events & libc::POLLIN != 0
+            && let Some(((len, from), stamps)) = receive(&socket, &mut buf, 0)?
+        {
+            let received_at = Instant::now();
+            if from == target && is_reply(&buf[..len], target, raw, ident, seq) {
+                break (stamps, received_at);
+            }
+        }
+    };
+    // The send timestamp is queued long before any reply, but check once more
+    if sent.is_none() {
+        sent = receive(&socket, &mut buf, libc::MSG_ERRQUEUE)?.map(|(_, stamps)| stamps);
+    }
+
+    let sent = sent.unwrap_or_default();
+    let between = |tx: Option<Duration>, rx: Option<Duration>| rx?.checked_sub(tx?);
+    Ok(
+        if let Some(rtt) = between(sent.hardware, received.hardware) {
+            (rtt, Clock::Hardware)
+        } else if let Some(rtt) = between(sent.software, received.software) {
+            (rtt, Clock::Kernel)
+        } else {
+            (received_at - sent_at, Clock::Userspace)
+        },
+    )
+}
+
+/// Open and configure the socket, returning whether it is a raw one
+fn open(target: IpAddr, options: &SocketOptions) -> Result<(Socket, bool)> {
+    let (domain, protocol) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => (socket, false),
+        Err(e) => {
+            debug!("No unprivileged ping socket ({}), trying a raw one", e);
+            let socket = Socket::new(domain, Type::RAW, Some(protocol)).context(
+                "Failed to open ICMP socket (--hw-timestamps needs ping sockets allowed by \
+                 net.ipv4.ping_group_range, or root or CAP_NET_RAW)",
+            )?;
+            (socket, true)
+        }
+    };
+    if let Some(source) = options.source {
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+    }
+    if let Some(interface) = options.interface {
+        crate::bind_device((&socket).into(), interface)?;
+    }
+    match target {
+        IpAddr::V4(_) => {
+            if let Some(ttl) = options.ttl {
+                socket.set_ttl_v4(ttl)?;
+            }
+            if let Some(dscp) = options.dscp {
+                socket.set_tos_v4(u32::from(dscp) << 2)?;
+            }
+        }
+        IpAddr::V6(_) => {
+            if let Some(ttl) = options.ttl {
+                socket.set_unicast_hops_v6(ttl)?;
+            }
+            if let Some(dscp) = options.dscp {
+                socket.set_tclass_v6(u32::from(dscp) << 2)?;
+            }
+        }
+    }
+
+    // SAFETY: the option value is a c_uint that lives across the call
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            ptr::from_ref(&TIMESTAMPING_FLAGS).cast(),
+            mem::size_of_val(&TIMESTAMPING_FLAGS) as libc::socklen_t,
+        )
+    };
+    if set != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to enable SO_TIMESTAMPING");
+    }
+    Ok((socket, raw))
+}
+
+/// Wait until the socket has a packet or a queued timestamp, returning the
+/// poll events (none if `timeout` passed first)
+fn wait(socket: &Socket, timeout: Duration) -> io::Result<libc::c_short> {
+    let mut fd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Round up, so a timeout under a millisecond doesn't spin
+    let millis = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int;
+    // SAFETY: `fd` is a single valid pollfd
+    match unsafe { libc::poll(&mut fd, 1, millis) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(0)
+            } else {
+                Err(e)
+            }
+        }
+        _ => Ok(fd.revents),
+    }
+}
+
+/// Read one packet (or, with MSG_ERRQUEUE, one send timestamp) without
+/// blocking, along with its length, sender, and timestamps. `None` if there was
+/// nothing to read.
+fn receive(
+    socket: &Socket,
+    buf: &mut [u8],
+    flags: libc::c_int,
+) -> io::Result<Option<((usize, IpAddr), Stamps)>> {
+    // SAFETY: all-zero bytes are valid for these plain C structs
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // u64 elements keep the control buffer aligned for cmsghdr
+    let mut control = [0u64; 64];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_name = ptr::from_mut(&mut name).cast();
+    message.msg_namelen = mem::size_of_val(&name) as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = mem::size_of_val(&control) as _;
+
+    // SAFETY: every pointer in `message` refers to a live buffer of the given size
+    let len =
+        unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, flags | libc::MSG_DONTWAIT) };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+            _ => Err(e),
+        };
+    }
+
+    let mut stamps = Stamps::default();
+    // SAFETY: the kernel filled in `message.msg_control` with well-formed cmsgs
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPING
+            {
+                // Software, legacy (unused), and raw hardware timestamps
+                let times: [libc::timespec; 3] = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                stamps.software = duration(&times[0]);
+                stamps.hardware = duration(&times[2]);
+            }
+            cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+        }
+    }
+    Ok(Some(((len as usize, address(&name)), stamps)))
+}
+
+/// A kernel timestamp, or `None` for the all-zero "not set" value
+fn duration(time: &libc::timespec) -> Option<Duration> {
+    (time.tv_sec != 0 || time.tv_nsec != 0)
+        .then(|| Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+fn address(name: &libc::sockaddr_storage) -> IpAddr {
+    // SAFETY: the family says which sockaddr the storage holds
+    unsafe {
+        match name.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr: libc::sockaddr_in = ptr::read(ptr::from_ref(name).cast());
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr: libc::sockaddr_in6 = ptr::read(ptr::from_ref(name).cast());
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+}
+
+/// Whether `packet` is the echo reply to our request. Raw IPv4 sockets deliver
+/// the IP header too. Ping sockets replace the identifier with their own and
+/// only deliver replies to it, so it is only checked on raw sockets.
+fn is_reply(packet: &[u8], target: IpAddr, raw: bool, ident: u16, seq: u16) -> bool {
+    let icmp = match target {
+        IpAddr::V4(_) if raw => {
+            let header_len = packet.first().map_or(0, |b| (b & 0x0f) as usize * 4);
+            packet.get(header_len..).unwrap_or_default()
+        }
+        _ => packet,
+    };
+    let echo_reply = match target {
+        IpAddr::V4(_) => ICMP_ECHO_REPLY,
+        IpAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    };
+    let Some(fields) = icmp.get(..8) else {
+        return false;
+    };
+    fields[0] == echo_reply
+        && (!raw || u16::from_be_bytes([fields[4], fields[5]]) == ident)
+        && u16::from_be_bytes([fields[6], fields[7]]) == seq
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tracing::debug;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+/// Length of the IPv6 header quoted in ICMPv6 errors (extension headers are not
+/// expected on echo requests)
+const IPV6_HEADER_LEN: usize = 40;
+
+/// One hop on the path to a host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hop {
+    /// TTL (hop limit) the probe was sent with
+    pub ttl: u8,
+    /// Router that reported the probe expired, or the host itself on the last hop;
+    /// null if nothing answered
+    pub ip: Option<IpAddr>,
+    /// Round-trip time of the probe in microseconds
+    pub rtt_microsecs: Option<i64>,
+}
+
+/// Trace the path to `target` by sending one ICMP echo request for every TTL
+/// from 1 to `max_hops` at once, then collecting the time-exceeded errors and
+/// echo replies that come back within `timeout`. The hop list ends at the first
+/// TTL the target itself answered; `ident` tells our probes' answers apart from
+/// other traffic. Needs a raw socket, so root or CAP_NET_RAW. Blocks the calling
+/// thread.
+pub fn traceroute(
+    target: IpAddr,
+    ident: u16,
+    max_hops: u8,
+    timeout: Duration,
+    payload_size: usize,
+    source: Option<IpAddr>,
+    interface: Option<&str>,
+) -> Result<Vec<Hop>> {
+    let (domain, protocol) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let socket = Socket::new(domain, Type::RAW, Some(protocol))
+        .context("Failed to open raw ICMP socket (traceroute needs root or CAP_NET_RAW)")?;
+    if let Some(source) = source {
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+    }
+    if let Some(interface) = interface {
+        crate::bind_device(SockRef::from(&socket), interface)?;
+    }
+    // A datagram socket wrapper gives safe send_to/recv_from over the raw fd
+    let socket = UdpSocket::from(socket);
+
+    let destination = SocketAddr::new(target, 0);
+    let payload = vec![0; payload_size];
+    let mut sent = HashMap::new();
+    for ttl in 1..=max_hops {
+        match target {
+            IpAddr::V4(_) => SockRef::from(&socket).set_ttl_v4(ttl as u32)?,
+            IpAddr::V6(_) => SockRef::from(&socket).set_unicast_hops_v6(ttl as u32)?,
+        }
+        let request = echo_request(target, ident, ttl as u16, &payload);
+        socket.send_to(&request, destination)?;
+        sent.insert(ttl, Instant::now());
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut answers: HashMap<u8, (IpAddr, Duration)> = HashMap::new();
+    // Lowest TTL that reached the target, once known
+    let mut reached: Option<u8> = None;
+    let mut buf = [0u8; 1500];
+    loop {
+        let last = reached.unwrap_or(max_hops);
+        if (1..=last).all(|ttl| answers.contains_key(&ttl)) {
+            break;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let Some((seq, from_target)) = parse_reply(&buf[..len], target, from.ip(), ident) else {
+            continue;
+        };
+        let Ok(ttl) = u8::try_from(seq) else {
+            continue;
+        };
+        let Some(sent_at) = sent.get(&ttl) else {
+            continue;
+        };
+        debug!("Trace to {}: hop {} is {}", target, ttl, from.ip());
+        answers.entry(ttl).or_insert((from.ip(), sent_at.elapsed()));
+        if from_target {
+            reached = Some(reached.map_or(ttl, |r| r.min(ttl)));
+        }
+    }
+
+    let last = reached.unwrap_or(max_hops);
+    Ok((1..=last)
+        .map(|ttl| {
+            let answer = answers.get(&ttl);
+            Hop {
+                ttl,
+                ip: answer.map(|(ip, _)| *ip),
+                rtt_microsecs: answer.map(|(_, rtt)| crate::microsecs(*rtt)),
+            }
+        })
+        .collect())
+}
+
+/// Build an echo request. The kernel fills in the ICMPv6 checksum itself.
+pub fn echo_request(target: IpAddr, ident: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let kind = match target {
+        IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+        IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+    };
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend_from_slice(&ident.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(payload);
+    if target.is_ipv4() {
+        let checksum = pnet_packet::util::checksum(&packet, 1);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Match a received packet to one of our probes, returning its sequence number
+/// and whether the target itself answered. IPv4 raw sockets deliver the IP
+/// header too; ICMPv6 ones do not.
+fn parse_reply(packet: &[u8], target: IpAddr, from: IpAddr, ident: u16) -> Option<(u16, bool)> {
+    let icmp = match target {
+        IpAddr::V4(_) => packet.get(header_len_v4(packet)?..)?,
+        IpAddr::V6(_) => packet,
+    };
+    let (echo_reply, errors, echo_request) = match target {
+        IpAddr::V4(_) => (
+            ICMP_ECHO_REPLY,
+            [ICMP_TIME_EXCEEDED, ICMP_DEST_UNREACHABLE],
+            ICMP_ECHO_REQUEST,
+        ),
+        IpAddr::V6(_) => (
+            ICMPV6_ECHO_REPLY,
+            [ICMPV6_TIME_EXCEEDED, ICMPV6_DEST_UNREACHABLE],
+            ICMPV6_ECHO_REQUEST,
+        ),
+    };
+
+    let kind = *icmp.first()?;
+    let echo = if kind == echo_reply {
+        if from != target {
+            return None;
+        }
+        icmp
+    } else if errors.contains(&kind) {
+        // Errors quote the IP header and first 8 bytes of the probe that caused them
+        let quoted = icmp.get(8..)?;
+        let quoted_header_len = match target {
+            IpAddr::V4(_) => header_len_v4(quoted)?,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        };
+        let probe = quoted.get(quoted_header_len..)?;
+        if *probe.first()? != echo_request {
+            return None;
+        }
+        probe
+    } else {
+        return None;
+    };
+
+    let fields = echo.get(4..8)?;
+    if u16::from_be_bytes([fields[0], fields[1]]) != ident {
+        return None;
+    }
+    Some((u16::from_be_bytes([fields[2], fields[3]]), from == target))
+}
+
+fn header_len_v4(packet: &[u8]) -> Option<usize> {
+    Some((*packet.first()? & 0x0f) as usize * 4)
+}